@@ -0,0 +1,51 @@
+use argh::FromArgs;
+
+use third_wheel::*;
+
+/// Generate a self-signed certificate authority for third-wheel to sign with
+#[derive(FromArgs)]
+struct GenerateCa {
+    /// file to store the certificate in
+    #[argh(option, short = 'c', default = "\"ca/ca_certs/cert.pem\".to_string()")]
+    cert_file: String,
+
+    /// file to store the private key in
+    #[argh(option, short = 'k', default = "\"ca/ca_certs/key.pem\".to_string()")]
+    key_file: String,
+
+    /// subject of the certificate, in the same form as `openssl req -subj`
+    #[argh(
+        option,
+        short = 's',
+        default = "\"/C=US/ST=private/L=province/O=city/CN=hostname.example.com\".to_string()"
+    )]
+    subject: String,
+
+    /// number of days the certificate is valid for
+    #[argh(option, short = 'd', default = "365")]
+    days: u32,
+
+    /// use an ECDSA P-256 key rather than RSA-2048
+    #[argh(switch)]
+    ecdsa: bool,
+
+    /// passphrase to encrypt the key with
+    #[argh(option, short = 'p')]
+    passphrase: Option<String>,
+}
+
+fn main() -> Result<(), Error> {
+    let args: GenerateCa = argh::from_env();
+    let key_type = if args.ecdsa {
+        KeyType::EcdsaP256
+    } else {
+        KeyType::Rsa2048
+    };
+    let ca = CertificateAuthority::generate(&args.subject, key_type, args.days)?;
+    match args.passphrase {
+        Some(passphrase) => {
+            ca.save_pem_files_with_passphrase_on_key(&args.cert_file, &args.key_file, &passphrase)
+        }
+        None => ca.save_pem_files(&args.cert_file, &args.key_file),
+    }
+}
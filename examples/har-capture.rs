@@ -45,7 +45,7 @@ async fn copy_from_http_request_to_har(
 ) -> v1_2::Request {
     let method = parts.method.as_str().to_string();
     let url = format!("{}", parts.uri);
-    let http_version = "HTTP/1.1".to_string(); // Hardcoded for now because third-wheel only handles HTTP/1.1
+    let http_version = format!("{:?}", parts.version);
     let mut headers = Vec::new();
     for (name, value) in &parts.headers {
         headers.push(Headers {
@@ -146,7 +146,7 @@ async fn copy_from_http_response_to_har(
         "".to_string()
     };
 
-    let http_version = "HTTP/1.1".to_string(); // Hardcoded for now because third-wheel only handles HTTP/1.1
+    let http_version = format!("{:?}", parts.version);
 
     let body = String::from_utf8(body).unwrap(); // TODO: handle other encodings correctly
     let body_size = body.len() as i64;
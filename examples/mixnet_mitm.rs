@@ -1,18 +1,18 @@
 use argh::FromArgs;
 use http::header::{CONTENT_LENGTH, HOST};
-use http::Request;
+use http::{Method, Request};
 use hyper::{body::Bytes, service::Service};
 use http::Response;
 
 use hyper::Body;
 use rand::rngs::StdRng;
 use rand::{RngCore, SeedableRng};
-use reqwest::Url;
 use third_wheel::*;
 
 use std::time::Instant;
 
 const QUERY_PATH: &str = "/dns-query";
+const ODOH_TARGET: &str = "odoh.cloudflare-dns.com";
 const NUM_PROXIES: usize = 16;
 
 /// Run a TLS mitm proxy that does no modification to the traffic
@@ -61,40 +61,22 @@ async fn main() -> Result<(), Error> {
                 req_parts.headers.remove(CONTENT_LENGTH);
 
                 // Sample the next proxy
-                let next_proxy = format!("http://localhost:{}", 8080 + rng.next_u32() as usize % NUM_PROXIES);
+                let next_proxy = format!("localhost:{}", 8080 + rng.next_u32() as usize % NUM_PROXIES);
 
-                // Send the package to dummy target via another proxy
-                let proxy = reqwest::Proxy::https(next_proxy).unwrap();
-                let client = reqwest::Client::builder()
-                    .proxy(proxy)
-                    .danger_accept_invalid_certs(true)
-                    .build().unwrap();
-                let mut blind = Url::parse("https://odoh.cloudflare-dns.com").unwrap();
-                blind.set_path(QUERY_PATH);
+                // Send the package to the target via another proxy
+                req_parts.method = Method::POST;
+                req_parts.uri = QUERY_PATH.parse().unwrap();
+                let req = Request::<Body>::from_parts(req_parts, Body::from(query_body));
                 let parse_timer = init_timer.elapsed();
                 println!("PDT: {:.4?}", parse_timer);
 
-                let builder = {
-                    client.post(blind).headers(req_parts.headers)
-                };
+                let next_hop = Upstream::new(ODOH_TARGET, "443").with_proxy(UpstreamProxy::http(&next_proxy));
+                let response = third_wheel.call_to(next_hop, req).await?;
 
-                let raw_resp = builder.body(query_body).send().await.unwrap();
-                let status = raw_resp.status();
-                let version = raw_resp.version();
-                let headers = raw_resp.headers().clone();
-                let raw_resp = raw_resp.bytes().await.unwrap().to_vec();
-                let resp_len = raw_resp.len();
-
-                let response = Response::new(Body::from(raw_resp));
-
-                // println!("REP_RAW: {:?}", response);
-                let (mut parts, body) = response.into_parts();
-                parts.status = status;
-                parts.version = version;
-                parts.headers = headers;
-                parts.headers.insert(CONTENT_LENGTH, resp_len.to_string().parse().unwrap());
-                // parts.extensions = raw_resp.extensions().clone();
-                Response::from_parts(parts, body)
+                let (mut rep_parts, rep_body) = response.into_parts();
+                let raw_resp = hyper::body::to_bytes(rep_body).await?;
+                rep_parts.headers.insert(CONTENT_LENGTH, raw_resp.len().to_string().parse().unwrap());
+                Response::from_parts(rep_parts, Body::from(raw_resp))
             } 
             // Otherwise send the message to the target
             else {
@@ -120,7 +102,12 @@ async fn main() -> Result<(), Error> {
         };
         Box::pin(fut)
     });
-    let mitm_proxy = MitmProxy::builder(podoh_mitm, ca).build();
+    // Every hop's certificates are signed by the same CA
+    let hop_ca = openssl::x509::X509::from_pem(&std::fs::read(&args.cert_file)?)?;
+    let mitm_proxy = MitmProxy::builder(podoh_mitm, ca)
+        .upstream_resolver(StaticUpstream(Upstream::new(ODOH_TARGET, "443")))
+        .additional_root_certificates(vec![hop_ca])
+        .build();
     let (_, mitm_proxy_fut) = mitm_proxy.bind(format!("127.0.0.1:{}", args.port).parse().unwrap());
     mitm_proxy_fut.await.unwrap();
     Ok(())
@@ -2,20 +2,20 @@ use std::convert::TryInto;
 
 use argh::FromArgs;
 use http::header::{CONTENT_LENGTH, HOST};
-use http::Request;
-use hyper::{body::Bytes, service::Service};
+use http::{Method, Request};
+use hyper::body::Bytes;
 use http::Response;
 
 use hyper::Body;
 use rand::rngs::StdRng;
 use rand::SeedableRng;
-use reqwest::Url;
 use third_wheel::*;
 use odoh_rs::{compose, decrypt_query, parse, ObliviousDoHKeyPair, ObliviousDoHMessage, ObliviousDoHMessageType};
 
 use std::time::Instant;
 
 const QUERY_PATH: &str = "/dns-query";
+const ODOH_TARGET: &str = "odoh.cloudflare-dns.com";
 
 /// Run a TLS mitm proxy that does no modification to the traffic
 #[derive(FromArgs)]
@@ -42,7 +42,7 @@ async fn main() -> Result<(), Error> {
         "third-wheel",
     )?;
     let port = args.port;
-    let podoh_mitm = mitm_layer(move |req: Request<Body>, mut third_wheel: ThirdWheel| {
+    let podoh_mitm = mitm_layer(move |req: Request<Body>, third_wheel: ThirdWheel| {
         let proxy_label: u64 = (port - 8080).into();
         let fut = async move {
             let mut rng = StdRng::seed_from_u64(proxy_label);
@@ -87,49 +87,31 @@ async fn main() -> Result<(), Error> {
                 req_parts.headers.remove(HOST);
                 req_parts.headers.remove(CONTENT_LENGTH);
 
-                // Send the package to dummy target via another proxy
-                let proxy = reqwest::Proxy::https(next_url_str).unwrap();
-                let client = reqwest::Client::builder()
-                    .proxy(proxy)
-                    .danger_accept_invalid_certs(true)
-                    .build().unwrap();
-                let mut blind = Url::parse("https://www.google.com").unwrap();
-                blind.set_path(QUERY_PATH);
+                // Send the package to the target via another proxy
+                req_parts.method = Method::POST;
+                req_parts.uri = QUERY_PATH.parse().unwrap();
+                let req = Request::<Body>::from_parts(req_parts, Body::from(query_body));
                 let parse_timer = init_timer.elapsed();
                 println!("PDT: {:.4?}", parse_timer);
                 let parse_timer = format!("{:.4?} + ", parse_timer);
                 let parse_timer_bytes = parse_timer.as_bytes();
                 let mut parse_timer_bytes_len: u8 = parse_timer_bytes.len().try_into().unwrap();
 
-                let builder = {
-                    client.post(blind).headers(req_parts.headers)
-                };
+                let next_proxy = next_url_str.trim_start_matches("http://");
+                let next_hop = Upstream::new(ODOH_TARGET, "443").with_proxy(UpstreamProxy::http(next_proxy));
+                let response = third_wheel.call_to(next_hop, req).await?;
 
-                let raw_resp = builder.body(query_body.to_vec()).send().await.unwrap();
-                let status = raw_resp.status();
-                let version = raw_resp.version();
-                let headers = raw_resp.headers().clone();
-                let mut raw_resp = raw_resp.bytes().await.unwrap().to_vec();
+                let (mut rep_parts, rep_body) = response.into_parts();
+                let mut raw_resp = hyper::body::to_bytes(rep_body).await?.to_vec();
                 parse_timer_bytes_len += raw_resp[0];
                 // Add parse time to the response message
-                raw_resp = [&[parse_timer_bytes_len], parse_timer_bytes, &raw_resp[1..]].concat().into();
-                let resp_len = raw_resp.len();
-
-                let response = Response::new(Body::from(raw_resp));
-
-                // println!("REP_RAW: {:?}", response);
-                let (mut parts, body) = response.into_parts();
-                parts.status = status;
-                parts.version = version;
-                parts.headers = headers;
-                parts.headers.insert(CONTENT_LENGTH, resp_len.to_string().parse().unwrap());
-                // parts.extensions = raw_resp.extensions().clone();
-                Response::from_parts(parts, body)
+                raw_resp = [&[parse_timer_bytes_len], parse_timer_bytes, &raw_resp[1..]].concat();
+                rep_parts.headers.insert(CONTENT_LENGTH, raw_resp.len().to_string().parse().unwrap());
+                Response::from_parts(rep_parts, Body::from(raw_resp))
             } 
             // Otherwise send the message to the target
             else {
                 // Reconstruct the header to match the new content length
-                req_parts.headers.insert(HOST, next_url_str.parse().unwrap());
                 req_parts.headers.insert(CONTENT_LENGTH, query_body.len().to_string().parse().unwrap());
                 let body = Body::from(query_body);
                 let req = Request::<Body>::from_parts(req_parts, body);
@@ -139,7 +121,7 @@ async fn main() -> Result<(), Error> {
                 let parse_timer_bytes = parse_timer.as_bytes();
                 let parse_timer_bytes_len: u8 = parse_timer_bytes.len().try_into().unwrap();
 
-                let response = third_wheel.call(req).await?;
+                let response = third_wheel.call_to(Upstream::new(next_url_str, "443"), req).await?;
                 
                 let (mut rep_parts, rep_body) = response.into_parts();
                 let body_bytes = hyper::body::to_bytes(rep_body).await?.to_vec();
@@ -153,7 +135,12 @@ async fn main() -> Result<(), Error> {
         };
         Box::pin(fut)
     });
-    let mitm_proxy = MitmProxy::builder(podoh_mitm, ca).build();
+    // Every hop's certificates are signed by the same CA
+    let hop_ca = openssl::x509::X509::from_pem(&std::fs::read(&args.cert_file)?)?;
+    let mitm_proxy = MitmProxy::builder(podoh_mitm, ca)
+        .upstream_resolver(StaticUpstream(Upstream::new(ODOH_TARGET, "443")))
+        .additional_root_certificates(vec![hop_ca])
+        .build();
     let (_, mitm_proxy_fut) = mitm_proxy.bind(format!("127.0.0.1:{}", args.port).parse().unwrap());
     mitm_proxy_fut.await.unwrap();
     Ok(())
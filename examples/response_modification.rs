@@ -39,7 +39,10 @@ async fn main() -> Result<(), Error> {
             .body(Body::from(modified_response.clone()))
             .unwrap())))
     });
-    let mitm_proxy = MitmProxy::builder(modifying_mitm, ca).build();
+    // The real server is never asked, so don't connect to it
+    let mitm_proxy = MitmProxy::builder(modifying_mitm, ca)
+        .lazy_upstream_connection(true)
+        .build();
     let (_, mitm_proxy_fut) = mitm_proxy.bind(format!("127.0.0.1:{}", args.port).parse().unwrap());
     mitm_proxy_fut.await.unwrap();
     Ok(())
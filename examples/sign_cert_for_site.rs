@@ -6,6 +6,7 @@ use third_wheel::*;
 
 fn run_sign_certificate_for_domain(
     outfile: &str,
+    key_outfile: &str,
     cert_file: &str,
     key_file: &str,
     domain: &str,
@@ -20,14 +21,17 @@ fn run_sign_certificate_for_domain(
     } else {
         CertificateAuthority::load_from_pem_files(cert_file, key_file)?
     };
-    let site_cert = create_signed_certificate_for_domain(domain, &ca)?;
+    let site_key = KeyType::Rsa2048.generate()?;
+    let site_cert = create_signed_certificate_for_domain_with_key(domain, &site_key, &ca)?;
 
     let mut site_cert_file = File::create(outfile)?;
     site_cert_file.write_all(&site_cert.to_pem()?)?;
+    let mut site_key_file = File::create(key_outfile)?;
+    site_key_file.write_all(&site_key.private_key_to_pem_pkcs8()?)?;
     Ok(())
 }
 
-/// Sign a x509 v2 certificate for a given domain and save it out to a file
+/// Sign a x509 v2 certificate for a given domain and save it and its new private key out to files
 #[derive(FromArgs)]
 struct SignRequest {
     /// domain to sign the certificate for
@@ -38,6 +42,10 @@ struct SignRequest {
     #[argh(option, short = 'o', default = "\"site.pem\".to_string()")]
     outfile: String,
 
+    /// file to store the certificate's private key in
+    #[argh(option, short = 'K', default = "\"site-key.pem\".to_string()")]
+    key_outfile: String,
+
     /// pem file containing the ca certificate
     #[argh(
         option,
@@ -59,6 +67,7 @@ fn main() -> Result<(), Error> {
     let up: SignRequest = argh::from_env();
     run_sign_certificate_for_domain(
         &up.outfile,
+        &up.key_outfile,
         &up.ca_cert_file,
         &up.ca_key_file,
         &up.domain,
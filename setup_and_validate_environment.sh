@@ -4,11 +4,9 @@ trap 'kill $(jobs -p)' EXIT
 set -e
 set -o xtrace
 
-pushd ./ca/ca_certs
-openssl req -x509 -newkey rsa:4096 -keyout key.pem -out cert.pem -days 365 -passout pass:"third-wheel" -subj "/C=US/ST=private/L=province/O=city/CN=hostname.example.com"
-popd
-cargo run --example sign_cert_for_site -- my_test_site.com -o ca/simple_server/localhost.pem -p third-wheel
-cat ca/ca_certs/key.pem >> ca/simple_server/localhost.pem
+cargo run --example generate_ca -- -c ca/ca_certs/cert.pem -k ca/ca_certs/key.pem -p third-wheel
+cargo run --example sign_cert_for_site -- my_test_site.com -o ca/simple_server/localhost.pem -K ca/simple_server/localhost-key.pem -p third-wheel
+cat ca/simple_server/localhost-key.pem >> ca/simple_server/localhost.pem
 pushd ./ca/simple_server
 python3 server.py <(echo "third-wheel") &
 echo "Sleeping to let python server wake up"
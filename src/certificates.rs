@@ -1,18 +1,30 @@
 use log::debug;
-use std::io;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
 use std::{fs::File, path::Path};
 
 use openssl::asn1::Asn1Time;
 use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
 use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
 use openssl::pkcs12::Pkcs12;
-use openssl::pkey::{PKey, Private};
+use openssl::pkey::{Id, PKey, PKeyRef, Private};
 use openssl::rsa::Rsa;
 use openssl::stack::Stack;
-use openssl::x509::extension::SubjectAlternativeName;
-use openssl::x509::{GeneralNameRef, X509Name, X509NameBuilder, X509NameRef, X509};
+use openssl::symm::Cipher;
+use openssl::x509::extension::{
+    AuthorityKeyIdentifier, BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAlternativeName,
+    SubjectKeyIdentifier,
+};
+use openssl::x509::{GeneralNameRef, X509Builder, X509Name, X509NameBuilder, X509NameRef, X509};
 
 use crate::error::Error;
+use crate::tls::{server_identity, ServerIdentity};
+
+use self::cache::{CertificateCache, IdentitySource};
+
+pub(crate) mod cache;
 
 /// A certificate authority to use for impersonating websites during the
 /// man-in-the-middle. The client must trust the given certificate for it to
@@ -29,6 +41,8 @@ impl CertificateAuthority {
     /// not require a passphrase (e.g. was created with the `-nodes` option on
     /// openssl). NB: There is a bug/behaviour in Mac OS X that prevents opening
     /// unencrypted key files.
+    ///
+    /// The key may be RSA, EC or Ed25519, in PKCS#8 or the traditional format.
     pub fn load_from_pem_files<P: AsRef<Path>, Q: AsRef<Path>>(
         cert_file: P,
         key_file: Q,
@@ -36,7 +50,7 @@ impl CertificateAuthority {
         let cert = X509::from_pem(&get_bytes_from_file(cert_file)?)?;
 
         let key = get_bytes_from_file(key_file)?;
-        let key = PKey::from_rsa(Rsa::private_key_from_pem(&key)?)?;
+        let key = PKey::private_key_from_pem(&key)?;
 
         Ok(Self { cert, key })
     }
@@ -51,13 +65,304 @@ impl CertificateAuthority {
         let cert = X509::from_pem(&get_bytes_from_file(cert_file)?)?;
 
         let key = get_bytes_from_file(key_file)?;
-        let key = PKey::from_rsa(Rsa::private_key_from_pem_passphrase(
-            &key,
-            passphrase.as_bytes(),
-        )?)?;
+        let key = PKey::private_key_from_pem_passphrase(&key, passphrase.as_bytes())?;
 
         Ok(Self { cert, key })
     }
+
+    /// Load certificate authority from DER formatted files. The key file must
+    /// not be encrypted and may be PKCS#8 or the traditional format.
+    pub fn load_from_der_files<P: AsRef<Path>, Q: AsRef<Path>>(
+        cert_file: P,
+        key_file: Q,
+    ) -> Result<Self, Error> {
+        let cert = X509::from_der(&get_bytes_from_file(cert_file)?)?;
+
+        let key = get_bytes_from_file(key_file)?;
+        let key = PKey::private_key_from_der(&key)?;
+
+        Ok(Self { cert, key })
+    }
+
+    /// Load certificate authority from a PKCS#12 bundle holding both the
+    /// certificate and its key
+    pub fn load_from_pkcs12_file<P: AsRef<Path>>(file: P, passphrase: &str) -> Result<Self, Error> {
+        let pkcs12 = Pkcs12::from_der(&get_bytes_from_file(file)?)?.parse2(passphrase)?;
+
+        Ok(Self {
+            cert: pkcs12
+                .cert
+                .ok_or_else(|| Error::IncompletePkcs12("certificate".to_string()))?,
+            key: pkcs12
+                .pkey
+                .ok_or_else(|| Error::IncompletePkcs12("private key".to_string()))?,
+        })
+    }
+
+    /// Generate a new self-signed certificate authority.
+    ///
+    /// The subject is given in the same form as `openssl req -subj`, e.g.
+    /// `/C=US/O=third-wheel/CN=third-wheel.example.com`, and the certificate is
+    /// valid from now for `validity_days` days.
+    pub fn generate(subject: &str, key_type: KeyType, validity_days: u32) -> Result<Self, Error> {
+        let key = key_type.generate()?;
+        let subject = parse_subject(subject)?;
+
+        let mut cert_builder = X509::builder()?;
+        cert_builder.set_version(2)?;
+        let serial_number = {
+            let mut serial_number = BigNum::new()?;
+            serial_number.rand(159, MsbOption::MAYBE_ZERO, false)?;
+            serial_number.to_asn1_integer()?
+        };
+        cert_builder.set_serial_number(&serial_number)?;
+        cert_builder.set_subject_name(&subject)?;
+        cert_builder.set_issuer_name(&subject)?;
+        cert_builder.set_pubkey(&key)?;
+        cert_builder.set_not_before((Asn1Time::days_from_now(0)?).as_ref())?;
+        cert_builder.set_not_after((Asn1Time::days_from_now(validity_days)?).as_ref())?;
+
+        cert_builder.append_extension(BasicConstraints::new().critical().ca().build()?)?;
+        cert_builder.append_extension(
+            KeyUsage::new()
+                .critical()
+                .key_cert_sign()
+                .crl_sign()
+                .build()?,
+        )?;
+        let subject_key_identifier =
+            SubjectKeyIdentifier::new().build(&cert_builder.x509v3_context(None, None))?;
+        cert_builder.append_extension(subject_key_identifier)?;
+
+        cert_builder.sign(&key, signing_digest(&key)?)?;
+
+        Ok(Self {
+            cert: cert_builder.build(),
+            key,
+        })
+    }
+
+    /// Save the certificate and an unencrypted PKCS#8 key as PEM files
+    pub fn save_pem_files<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        cert_file: P,
+        key_file: Q,
+    ) -> Result<(), Error> {
+        write_bytes_to_file(cert_file, &self.cert.to_pem()?)?;
+        write_bytes_to_file(key_file, &self.key.private_key_to_pem_pkcs8()?)
+    }
+
+    /// Save the certificate and a PKCS#8 key encrypted with `passphrase` as PEM
+    /// files. These can be read back with
+    /// `load_from_pem_files_with_passphrase_on_key`.
+    pub fn save_pem_files_with_passphrase_on_key<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        cert_file: P,
+        key_file: Q,
+        passphrase: &str,
+    ) -> Result<(), Error> {
+        write_bytes_to_file(cert_file, &self.cert.to_pem()?)?;
+        write_bytes_to_file(
+            key_file,
+            &self.key.private_key_to_pem_pkcs8_passphrase(
+                Cipher::aes_256_cbc(),
+                passphrase.as_bytes(),
+            )?,
+        )
+    }
+
+    /// Save the certificate and unencrypted key as DER files
+    pub fn save_der_files<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        cert_file: P,
+        key_file: Q,
+    ) -> Result<(), Error> {
+        write_bytes_to_file(cert_file, &self.cert.to_der()?)?;
+        write_bytes_to_file(key_file, &self.key.private_key_to_der()?)
+    }
+
+    /// Save the certificate and key together as a PKCS#12 bundle protected by
+    /// `passphrase`, which is the format most OS and browser trust stores import
+    pub fn save_pkcs12_file<P: AsRef<Path>>(&self, file: P, passphrase: &str) -> Result<(), Error> {
+        let pkcs12 = Pkcs12::builder()
+            .name("third-wheel")
+            .pkey(&self.key)
+            .cert(&self.cert)
+            .build2(passphrase)?
+            .to_der()?;
+        write_bytes_to_file(file, &pkcs12)
+    }
+}
+
+/// The digest to sign certificates with for the given key. `EdDSA` keys hash
+/// internally so must be given the null digest.
+fn signing_digest(key: &PKeyRef<Private>) -> Result<MessageDigest, Error> {
+    let digest = match key.id() {
+        Id::ED25519 | Id::ED448 => MessageDigest::null(),
+        Id::EC => match key.ec_key()?.group().curve_name() {
+            Some(Nid::SECP384R1) => MessageDigest::sha384(),
+            Some(Nid::SECP521R1) => MessageDigest::sha512(),
+            _ => MessageDigest::sha256(),
+        },
+        _ => MessageDigest::sha256(),
+    };
+    Ok(digest)
+}
+
+/// Parse an openssl style subject such as `/C=US/CN=example.com`
+fn parse_subject(subject: &str) -> Result<X509Name, Error> {
+    let mut name = X509Name::builder()?;
+    for entry in subject.split('/').filter(|entry| !entry.is_empty()) {
+        let mut field_and_value = entry.splitn(2, '=');
+        match (field_and_value.next(), field_and_value.next()) {
+            (Some(field), Some(value)) => name.append_entry_by_text(field, value)?,
+            _ => return Err(Error::InvalidSubject(subject.to_string())),
+        }
+    }
+    Ok(name.build())
+}
+
+/// The algorithm used when generating a new key pair
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyType {
+    Rsa2048,
+    EcdsaP256,
+}
+
+impl KeyType {
+    /// Generate a fresh private key of this type
+    pub fn generate(self) -> Result<PKey<Private>, Error> {
+        let key = match self {
+            Self::Rsa2048 => PKey::from_rsa(Rsa::generate(2048)?)?,
+            Self::EcdsaP256 => {
+                let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+                PKey::from_ec_key(EcKey::generate(&group)?)?
+            }
+        };
+        Ok(key)
+    }
+}
+
+/// Which key pair spoofed leaf certificates are issued for. Leaves never
+/// share the certificate authority's own key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LeafKeyStrategy {
+    /// Generate one key when the proxy first needs it and reuse it for every
+    /// host. This is the cheapest option and is the default.
+    PerProxy(KeyType),
+    /// Generate a new key for every host the proxy impersonates
+    PerHost(KeyType),
+}
+
+impl Default for LeafKeyStrategy {
+    fn default() -> Self {
+        Self::PerProxy(KeyType::Rsa2048)
+    }
+}
+
+pub(crate) struct LeafKeys {
+    strategy: LeafKeyStrategy,
+    shared: Mutex<Option<PKey<Private>>>,
+}
+
+impl LeafKeys {
+    pub(crate) const fn new(strategy: LeafKeyStrategy) -> Self {
+        Self {
+            strategy,
+            shared: Mutex::new(None),
+        }
+    }
+
+    /// The key to put in the next spoofed certificate
+    pub(crate) fn next_key(&self) -> Result<PKey<Private>, Error> {
+        match self.strategy {
+            LeafKeyStrategy::PerHost(key_type) => key_type.generate(),
+            LeafKeyStrategy::PerProxy(key_type) => {
+                let mut shared = self.shared.lock().expect("leaf key lock poisoned");
+                if let Some(key) = shared.as_ref() {
+                    return Ok(key.clone());
+                }
+                let key = key_type.generate()?;
+                *shared = Some(key.clone());
+                drop(shared);
+                Ok(key)
+            }
+        }
+    }
+}
+
+/// Everything the proxy needs to impersonate an upstream server to the client
+pub(crate) struct CertificateSpoofer {
+    ca: CertificateAuthority,
+    leaf_keys: LeafKeys,
+    cache: Arc<CertificateCache>,
+}
+
+impl CertificateSpoofer {
+    pub(crate) const fn new(
+        ca: CertificateAuthority,
+        leaf_keys: LeafKeys,
+        cache: Arc<CertificateCache>,
+    ) -> Self {
+        Self {
+            ca,
+            leaf_keys,
+            cache,
+        }
+    }
+
+    /// The identity to present to clients in place of `host`, whose real
+    /// certificate is `upstream_certificate`
+    pub(crate) fn identity_for(
+        &self,
+        host: &str,
+        upstream_certificate: &X509,
+    ) -> Result<ServerIdentity, Error> {
+        let fingerprint = upstream_certificate.digest(MessageDigest::sha256())?;
+        let source = IdentitySource::Upstream(fingerprint.to_vec());
+        self.cache.get_or_insert_with(host, source, || {
+            let key = self.leaf_keys.next_key()?;
+            let certificate = spoof_certificate(upstream_certificate, &key, &self.ca)?;
+            server_identity(&certificate, &key)
+        })
+    }
+
+    /// The identity to present to clients for `host` without having seen its
+    /// real certificate
+    pub(crate) fn identity_for_domain(&self, host: &str) -> Result<ServerIdentity, Error> {
+        self.cache
+            .get_or_insert_with(host, IdentitySource::Domain, || {
+                let key = self.leaf_keys.next_key()?;
+                let certificate =
+                    create_signed_certificate_for_domain_with_key(host, &key, &self.ca)?;
+                server_identity(&certificate, &key)
+            })
+    }
+
+    /// A copy of `upstream_certificate` signed by its own key rather than the
+    /// CA, so clients distrust it as they would have the original
+    pub(crate) fn self_signed_identity_for(
+        &self,
+        upstream_certificate: &X509,
+    ) -> Result<ServerIdentity, Error> {
+        let key = self.leaf_keys.next_key()?;
+        let certificate = spoof_self_signed_certificate(upstream_certificate, &key)?;
+        server_identity(&certificate, &key)
+    }
+
+    /// A copy of `upstream_certificate` signed by the CA but already expired,
+    /// so clients distrust it as they would have the original
+    pub(crate) fn expired_identity_for(
+        &self,
+        upstream_certificate: &X509,
+    ) -> Result<ServerIdentity, Error> {
+        let key = self.leaf_keys.next_key()?;
+        let mut cert_builder = spoofed_certificate_builder(upstream_certificate)?;
+        cert_builder.set_not_before(Asn1Time::from_str("20000101000000Z")?.as_ref())?;
+        cert_builder.set_not_after(Asn1Time::from_str("20010101000000Z")?.as_ref())?;
+        let certificate = sign_leaf(cert_builder, &key, &self.ca)?;
+        server_identity(&certificate, &key)
+    }
 }
 
 fn get_bytes_from_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, Error> {
@@ -67,24 +372,87 @@ fn get_bytes_from_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, Error> {
     Ok(bytes)
 }
 
-pub(crate) fn native_identity(
-    certificate: &X509,
-    key: &PKey<Private>,
-) -> Result<native_tls::Identity, Error> {
-    let pkcs = Pkcs12::builder()
-        .build("third-wheel", "", key, certificate)?
-        .to_der()?;
-    let identity = native_tls::Identity::from_pkcs12(&pkcs, "third-wheel")?;
-    Ok(identity)
+fn write_bytes_to_file<P: AsRef<Path>>(path: P, bytes: &[u8]) -> Result<(), Error> {
+    let mut file = File::create(path)?;
+    file.write_all(bytes)?;
+    Ok(())
+}
+
+/// A certificate, optionally followed by its chain, and private key to present
+/// to upstreams that require mutual TLS
+#[derive(Clone)]
+pub struct ClientIdentity {
+    pub(crate) certificate: X509,
+    pub(crate) chain: Vec<X509>,
+    pub(crate) key: PKey<Private>,
+}
+
+impl ClientIdentity {
+    #[must_use]
+    pub const fn new(certificate: X509, chain: Vec<X509>, key: PKey<Private>) -> Self {
+        Self {
+            certificate,
+            chain,
+            key,
+        }
+    }
+
+    /// Load an identity from a DER encoded PKCS#12 archive
+    pub fn from_pkcs12(der: &[u8], passphrase: &str) -> Result<Self, Error> {
+        let pkcs12 = Pkcs12::from_der(der)?.parse2(passphrase)?;
+        let certificate = pkcs12
+            .cert
+            .ok_or_else(|| Error::IncompletePkcs12("certificate".to_string()))?;
+        let key = pkcs12
+            .pkey
+            .ok_or_else(|| Error::IncompletePkcs12("private key".to_string()))?;
+        let chain = pkcs12
+            .ca
+            .map(|chain| chain.into_iter().collect())
+            .unwrap_or_default();
+        Ok(Self::new(certificate, chain, key))
+    }
+}
+
+/// Load an identity to present to upstreams that require mutual TLS from a
+/// PEM certificate file, optionally followed by its chain, and a PEM private
+/// key file of any key type
+pub fn load_client_identity_from_pem_files<P: AsRef<Path>, Q: AsRef<Path>>(
+    cert_file: P,
+    key_file: Q,
+) -> Result<ClientIdentity, Error> {
+    let mut certificates = X509::stack_from_pem(&get_bytes_from_file(cert_file)?)?.into_iter();
+    let certificate = certificates
+        .next()
+        .ok_or_else(|| Error::RequestError("No certificate found in PEM file".to_string()))?;
+    let key = PKey::private_key_from_pem(&get_bytes_from_file(key_file)?)?;
+    Ok(ClientIdentity::new(
+        certificate,
+        certificates.collect(),
+        key,
+    ))
 }
 
-/// Sign a certificate for this domain
+/// Sign a certificate for this domain for a new RSA key of its own
+///
+/// The new key is thrown away, so the certificate can be inspected but not
+/// served. Use `create_signed_certificate_for_domain_with_key` to keep it.
+pub fn create_signed_certificate_for_domain(
+    domain: &str,
+    ca: &CertificateAuthority,
+) -> Result<X509, Error> {
+    let key = KeyType::Rsa2048.generate()?;
+    create_signed_certificate_for_domain_with_key(domain, &key, ca)
+}
+
+/// Sign a certificate for this domain carrying the public half of `key`
 ///
 /// This function does not intelligently spoof fields like in the mitm proxy because
 /// it does not call the actual domain to get that information. As such, this may be
 /// rejected by browsers.
-pub fn create_signed_certificate_for_domain(
+pub fn create_signed_certificate_for_domain_with_key(
     domain: &str,
+    key: &PKeyRef<Private>,
     ca: &CertificateAuthority,
 ) -> Result<X509, Error> {
     let mut cert_builder = X509::builder()?;
@@ -110,9 +478,31 @@ pub fn create_signed_certificate_for_domain(
         .build(&cert_builder.x509v3_context(Some(&ca.cert), None))?;
     cert_builder.append_extension(subject_alternative_name)?;
 
-    cert_builder.set_issuer_name(ca.cert.issuer_name())?;
-    cert_builder.set_pubkey(&ca.key)?;
-    cert_builder.sign(&ca.key, MessageDigest::sha256())?;
+    sign_leaf(cert_builder, key, ca)
+}
+
+/// Finish a leaf certificate: issue it from the CA for `key` and add the
+/// extensions strict clients expect of a TLS server certificate
+fn sign_leaf(
+    mut cert_builder: X509Builder,
+    key: &PKeyRef<Private>,
+    ca: &CertificateAuthority,
+) -> Result<X509, Error> {
+    cert_builder.set_issuer_name(ca.cert.subject_name())?;
+    cert_builder.set_pubkey(key)?;
+
+    cert_builder.append_extension(BasicConstraints::new().critical().build()?)?;
+    cert_builder.append_extension(ExtendedKeyUsage::new().server_auth().build()?)?;
+    let subject_key_identifier =
+        SubjectKeyIdentifier::new().build(&cert_builder.x509v3_context(Some(&ca.cert), None))?;
+    cert_builder.append_extension(subject_key_identifier)?;
+    let authority_key_identifier = AuthorityKeyIdentifier::new()
+        .keyid(false)
+        .issuer(false)
+        .build(&cert_builder.x509v3_context(Some(&ca.cert), None))?;
+    cert_builder.append_extension(authority_key_identifier)?;
+
+    cert_builder.sign(&ca.key, signing_digest(&ca.key)?)?;
 
     Ok(cert_builder.build())
 }
@@ -159,10 +549,31 @@ fn copy_alt_names(in_cert: &X509) -> Option<SubjectAlternativeName> {
     }
 }
 
-pub(crate) fn spoof_certificate(
+fn spoof_certificate(
     certificate: &X509,
+    key: &PKeyRef<Private>,
     ca: &CertificateAuthority,
 ) -> Result<X509, Error> {
+    sign_leaf(spoofed_certificate_builder(certificate)?, key, ca)
+}
+
+/// Like `spoof_certificate`, but issued by the certificate itself
+fn spoof_self_signed_certificate(
+    certificate: &X509,
+    key: &PKeyRef<Private>,
+) -> Result<X509, Error> {
+    let mut cert_builder = spoofed_certificate_builder(certificate)?;
+    cert_builder.set_issuer_name(certificate.subject_name())?;
+    cert_builder.set_pubkey(key)?;
+    cert_builder.append_extension(BasicConstraints::new().critical().build()?)?;
+    cert_builder.append_extension(ExtendedKeyUsage::new().server_auth().build()?)?;
+    cert_builder.sign(key, signing_digest(key)?)?;
+    Ok(cert_builder.build())
+}
+
+/// Start a certificate with the subject, validity, serial number and alt
+/// names of `certificate`
+fn spoofed_certificate_builder(certificate: &X509) -> Result<X509Builder, Error> {
     let mut cert_builder = X509::builder()?;
 
     let name: &X509NameRef = certificate.subject_name();
@@ -177,15 +588,11 @@ pub(crate) fn spoof_certificate(
 
     if let Some(subject_alternative_name) = copy_alt_names(certificate) {
         let subject_alternative_name =
-            subject_alternative_name.build(&cert_builder.x509v3_context(Some(&ca.cert), None))?;
+            subject_alternative_name.build(&cert_builder.x509v3_context(None, None))?;
         cert_builder.append_extension(subject_alternative_name)?;
     }
 
-    cert_builder.set_issuer_name(ca.cert.issuer_name())?;
-    cert_builder.set_pubkey(&ca.key)?;
-    cert_builder.sign(&ca.key, MessageDigest::sha256())?;
-
-    Ok(cert_builder.build())
+    Ok(cert_builder)
 }
 
 #[allow(dead_code, clippy::cognitive_complexity)]
@@ -266,3 +673,41 @@ fn print_general_name(general_name: &GeneralNameRef) {
         debug!("ipaddress: {:?}", ipaddress);
     }
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forged_leaf_is_a_proper_server_certificate() {
+        let ca = CertificateAuthority::generate("/CN=third-wheel", KeyType::EcdsaP256, 1).unwrap();
+        let key = KeyType::EcdsaP256.generate().unwrap();
+        let leaf = create_signed_certificate_for_domain_with_key("example.com", &key, &ca).unwrap();
+
+        assert!(leaf.public_key().unwrap().public_eq(&key));
+        assert!(!leaf.public_key().unwrap().public_eq(&ca.key));
+        assert!(leaf.verify(&ca.key).unwrap());
+        assert_eq!(
+            leaf.issuer_name().to_der().unwrap(),
+            ca.cert.subject_name().to_der().unwrap()
+        );
+        assert!(leaf.subject_key_id().is_some());
+        assert_eq!(
+            leaf.authority_key_id().unwrap().as_slice(),
+            ca.cert.subject_key_id().unwrap().as_slice()
+        );
+
+        let text = String::from_utf8(leaf.to_text().unwrap()).unwrap();
+        assert!(text.contains("X509v3 Basic Constraints: critical\n                CA:FALSE"));
+        assert!(text.contains("TLS Web Server Authentication"));
+    }
+
+    #[test]
+    fn leaf_without_a_key_gets_one_of_its_own() {
+        let ca = CertificateAuthority::generate("/CN=third-wheel", KeyType::EcdsaP256, 1).unwrap();
+        let leaf = create_signed_certificate_for_domain("example.com", &ca).unwrap();
+        assert!(!leaf.public_key().unwrap().public_eq(&ca.key));
+        assert!(leaf.verify(&ca.key).unwrap());
+    }
+}
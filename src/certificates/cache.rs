@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::error::Error;
+use crate::tls::ServerIdentity;
+
+/// What a cached identity was forged from, so that identities forged from the
+/// host name alone and those mirroring an upstream certificate for the same
+/// host are never served in place of each other
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum IdentitySource {
+    /// Only the host name, for tunnels that haven't seen the upstream's
+    /// certificate
+    Domain,
+    /// The upstream certificate with this SHA-256 fingerprint
+    Upstream(Vec<u8>),
+}
+
+type CacheKey = (String, IdentitySource);
+
+struct CacheEntry {
+    identity: ServerIdentity,
+    inserted: Instant,
+    last_used: Instant,
+}
+
+/// A bounded cache of spoofed TLS identities shared by every tunnel of a
+/// `MitmProxy`, so that a certificate only has to be forged and signed once
+/// per upstream host.
+///
+/// Entries older than the TTL are rebuilt on their next use, and when the
+/// cache is full the least recently used entry is evicted. A capacity of zero
+/// disables caching.
+pub struct CertificateCache {
+    capacity: usize,
+    ttl: Duration,
+    entries: Mutex<HashMap<CacheKey, CacheEntry>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl CertificateCache {
+    #[must_use]
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Look up the identity for `host` forged from `source`, building and
+    /// caching it with `make_identity` if there is no live entry
+    pub(crate) fn get_or_insert_with<F>(
+        &self,
+        host: &str,
+        source: IdentitySource,
+        make_identity: F,
+    ) -> Result<ServerIdentity, Error>
+    where
+        F: FnOnce() -> Result<ServerIdentity, Error>,
+    {
+        let key = (host.to_string(), source);
+        if let Some(identity) = self.get(&key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(identity);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        // Signing is slow so do it without holding the lock. Two tunnels racing
+        // for the same host may both forge a certificate, the last one wins.
+        let identity = make_identity()?;
+        self.insert(key, identity.clone());
+        Ok(identity)
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<ServerIdentity> {
+        let mut entries = self.entries();
+        let now = Instant::now();
+        match entries.get_mut(key) {
+            Some(entry) if now.duration_since(entry.inserted) < self.ttl => {
+                entry.last_used = now;
+                Some(entry.identity.clone())
+            }
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&self, key: CacheKey, identity: ServerIdentity) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries();
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            let least_recently_used = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(least_recently_used) = least_recently_used {
+                entries.remove(&least_recently_used);
+            }
+        }
+        let now = Instant::now();
+        entries.insert(
+            key,
+            CacheEntry {
+                identity,
+                inserted: now,
+                last_used: now,
+            },
+        );
+    }
+
+    /// A panic while the lock is held can't leave the map half updated, so
+    /// the entries are still used after one
+    fn entries(&self) -> MutexGuard<'_, HashMap<CacheKey, CacheEntry>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The number of tunnels that reused a cached identity
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// The number of tunnels that had to forge a new identity
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// The number of identities currently held, including expired ones that
+    /// have not been evicted yet
+    pub fn len(&self) -> usize {
+        self.entries().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drop every cached identity, e.g. after rotating the certificate authority
+    pub fn clear(&self) {
+        self.entries().clear();
+    }
+}
+
+impl Default for CertificateCache {
+    /// Room for 1024 hosts, each kept for an hour
+    fn default() -> Self {
+        Self::new(1024, Duration::from_hours(1))
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::certificates::{
+        create_signed_certificate_for_domain_with_key, CertificateAuthority, KeyType,
+    };
+    use crate::tls::server_identity;
+
+    fn identity() -> ServerIdentity {
+        let ca = CertificateAuthority::generate("/CN=third-wheel", KeyType::EcdsaP256, 1).unwrap();
+        let key = KeyType::EcdsaP256.generate().unwrap();
+        let certificate =
+            create_signed_certificate_for_domain_with_key("example.com", &key, &ca).unwrap();
+        server_identity(&certificate, &key).unwrap()
+    }
+
+    /// Look `host` up, returning whether a new identity had to be forged
+    fn forged(
+        cache: &CertificateCache,
+        identity: &ServerIdentity,
+        host: &str,
+        source: IdentitySource,
+    ) -> bool {
+        let mut forged = false;
+        cache
+            .get_or_insert_with(host, source, || {
+                forged = true;
+                Ok(identity.clone())
+            })
+            .unwrap();
+        forged
+    }
+
+    #[test]
+    fn hits_and_misses_are_counted() {
+        let identity = identity();
+        let cache = CertificateCache::default();
+        assert!(forged(&cache, &identity, "a.com", IdentitySource::Domain));
+        assert!(!forged(&cache, &identity, "a.com", IdentitySource::Domain));
+        assert!(forged(&cache, &identity, "b.com", IdentitySource::Domain));
+        assert_eq!(cache.hits(), 1);
+        assert_eq!(cache.misses(), 2);
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_entries_are_forged_again() {
+        let identity = identity();
+        let cache = CertificateCache::new(16, Duration::from_secs(30));
+        assert!(forged(&cache, &identity, "a.com", IdentitySource::Domain));
+        assert!(!forged(&cache, &identity, "a.com", IdentitySource::Domain));
+        tokio::time::advance(Duration::from_secs(31)).await;
+        assert!(forged(&cache, &identity, "a.com", IdentitySource::Domain));
+        assert_eq!(cache.misses(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn least_recently_used_entry_is_evicted() {
+        let identity = identity();
+        let cache = CertificateCache::new(2, Duration::from_secs(30));
+        assert!(forged(&cache, &identity, "a.com", IdentitySource::Domain));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(forged(&cache, &identity, "b.com", IdentitySource::Domain));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(!forged(&cache, &identity, "a.com", IdentitySource::Domain));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(forged(&cache, &identity, "c.com", IdentitySource::Domain));
+        assert_eq!(cache.len(), 2);
+        assert!(!forged(&cache, &identity, "a.com", IdentitySource::Domain));
+        assert!(!forged(&cache, &identity, "c.com", IdentitySource::Domain));
+        assert!(forged(&cache, &identity, "b.com", IdentitySource::Domain));
+    }
+
+    #[test]
+    fn zero_capacity_disables_caching() {
+        let identity = identity();
+        let cache = CertificateCache::new(0, Duration::from_secs(30));
+        assert!(forged(&cache, &identity, "a.com", IdentitySource::Domain));
+        assert!(forged(&cache, &identity, "a.com", IdentitySource::Domain));
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn identities_from_different_sources_are_kept_apart() {
+        let identity = identity();
+        let cache = CertificateCache::default();
+        let upstream = IdentitySource::Upstream(vec![1; 32]);
+        let rotated = IdentitySource::Upstream(vec![2; 32]);
+        assert!(forged(&cache, &identity, "a.com", IdentitySource::Domain));
+        assert!(forged(&cache, &identity, "a.com", upstream.clone()));
+        assert!(forged(&cache, &identity, "a.com", rotated));
+        assert!(!forged(&cache, &identity, "a.com", IdentitySource::Domain));
+        assert!(!forged(&cache, &identity, "a.com", upstream));
+        assert_eq!(cache.len(), 3);
+    }
+}
@@ -4,16 +4,20 @@ use thiserror::Error as ThisError;
 #[allow(clippy::pub_enum_variant_names)]
 #[derive(ThisError, Debug)]
 pub enum Error {
-    #[error("an error handling server responses")]
+    #[error("an error handling server responses: {0}")]
     ServerError(String),
-    #[error("an error handling client requests")]
+    #[error("an error handling client requests: {0}")]
     RequestError(String),
     #[error(transparent)]
     HyperError(#[from] hyper::Error),
     #[error(transparent)]
     IOError(#[from] io::Error),
+    #[cfg(feature = "native-tls")]
     #[error(transparent)]
     NativeTlsError(#[from] native_tls::Error),
+    #[cfg(feature = "rustls")]
+    #[error(transparent)]
+    RustlsError(#[from] rustls::Error),
     #[error(transparent)]
     OpenSslError(#[from] openssl::error::Error),
     #[error(transparent)]
@@ -22,4 +26,14 @@ pub enum Error {
     NonUtf8String(String),
     #[error(transparent)]
     InvalidUri(#[from] http::uri::InvalidUri),
+    #[error("PKCS#12 archive has no {0}")]
+    IncompletePkcs12(String),
+    #[error("certificate subject `{0}` is not of the form /KEY=value/KEY=value")]
+    InvalidSubject(String),
+    #[error("upstream certificate rejected: {0}")]
+    UpstreamCertificateRejected(String),
+    #[error("DNS resolution failed: {0}")]
+    DnsError(String),
+    #[error("WebSocket error: {0}")]
+    WebSocketError(String),
 }
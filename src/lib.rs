@@ -45,14 +45,33 @@
 
 pub(crate) mod certificates;
 pub(crate) mod proxy;
+pub(crate) mod tls;
 
 pub(crate) mod error;
 
-pub use crate::certificates::create_signed_certificate_for_domain;
-pub use crate::certificates::CertificateAuthority;
+pub use crate::certificates::cache::CertificateCache;
+pub use crate::certificates::{
+    create_signed_certificate_for_domain, create_signed_certificate_for_domain_with_key,
+    load_client_identity_from_pem_files, ClientIdentity,
+};
+pub use crate::certificates::{CertificateAuthority, KeyType, LeafKeyStrategy};
 pub use error::Error;
+#[cfg(feature = "odoh")]
+pub use proxy::resolver::ObliviousDohResolver;
 pub use proxy::{
+    client_hello::ClientSni,
+    connection_info::ConnectionInfo,
+    intercept::{HostPatterns, InterceptAll, InterceptFilter},
     mitm::{mitm_layer, ThirdWheel},
+    proxy_auth::ProxyAuthenticator,
+    resolver::{DohResolver, Resolver, StaticResolver, SystemResolver},
+    stream_interceptor::{DecryptedStream, Splice, StreamInterceptor},
+    tls_policy::{
+        spki_sha256, InvalidUpstreamCertificate, UpstreamTlsPolicy, UpstreamVerification,
+    },
+    upstream::{Passthrough, StaticUpstream, Upstream, UpstreamResolver, UpstreamRules},
+    upstream_proxy::UpstreamProxy,
+    websocket::{WebSocketDirection, WebSocketInterceptor, WebSocketMessage},
     MitmProxy, MitmProxyBuilder,
 };
 
@@ -1,36 +1,69 @@
 use futures::Future;
 use futures::FutureExt;
-use hyper::server::conn::Http;
-use hyper::{client::conn::Builder, service::Service};
-use native_tls::Certificate;
+use futures::StreamExt;
+use hyper::server::conn::{AddrStream, Http};
+use hyper::service::Service;
 use openssl::x509::X509;
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::AsyncRead;
 use tokio::io::AsyncWrite;
 use tokio::net::TcpStream;
 use tower::Layer;
 
-use http::{Request, Response};
+use http::{Request, Response, Version};
 
-use tokio_native_tls::{TlsAcceptor, TlsStream};
-
-use crate::certificates::spoof_certificate;
 use crate::error::Error;
 
 use log::error;
 
 use crate::{
-    certificates::{native_identity, CertificateAuthority},
+    certificates::{
+        cache::CertificateCache, CertificateAuthority, CertificateSpoofer, ClientIdentity,
+        LeafKeyStrategy, LeafKeys,
+    },
     proxy::mitm::ThirdWheel,
+    tls::{self, ServerIdentity, TlsConnector},
 };
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{server::Server, Body};
 
-use self::mitm::RequestSendingSynchronizer;
+use self::client_hello::{read_server_name, ClientSni};
+use self::connection_info::{ConnectionInfo, WithConnectionInfo};
+use self::connector::{alpn_protocols_for, negotiated_version, Connector, UpstreamTls};
+use self::intercept::{InterceptAll, InterceptFilter};
+use self::pool::{ConnectionPool, UpstreamConnection};
+use self::proxy_auth::ProxyAuthenticator;
+use self::resolver::{Resolver, StaticResolver, SystemResolver};
+use self::rewind::Rewind;
+use self::stream_interceptor::{sniff_http, Splice, StreamInterceptor};
+use self::tls_policy::{InvalidUpstreamCertificate, UpstreamTlsPolicy, UpstreamVerification};
+use self::upstream::{Passthrough, Upstream, UpstreamResolver};
+use self::upstream_proxy::UpstreamProxy;
+use self::websocket::WebSocketInterceptor;
 
+pub(crate) mod client_hello;
+pub(crate) mod connection_info;
+pub(crate) mod connector;
+pub(crate) mod intercept;
 pub(crate) mod mitm;
+pub(crate) mod pool;
+pub(crate) mod proxy_auth;
+pub(crate) mod reader;
+pub(crate) mod resolver;
+pub(crate) mod rewind;
+pub(crate) mod socks5;
+pub(crate) mod stream_interceptor;
+pub(crate) mod tls_policy;
+pub(crate) mod transparent;
+pub(crate) mod upstream;
+pub(crate) mod upstream_proxy;
+pub(crate) mod websocket;
+
+type ResponseFuture = Pin<Box<dyn Future<Output = Result<Response<Body>, Error>> + Send>>;
 
 // TODO: do this without macro hackery
 // The idea of using of a macro here is borrowed from warp after hitting my head against it for some time.
@@ -39,65 +72,129 @@ pub(crate) mod mitm;
 // either we should replace this with a private function on MitmProxy, or we should do *something else*
 macro_rules! make_service {
     ($this:ident) => {{
-        let ca = Arc::new($this.ca);
-        let mitm = $this.mitm_layer;
-        let additional_host_mapping = $this.additional_host_mappings;
-        let additional_root_certificates = $this.additional_root_certificates;
-        make_service_fn(move |_| {
+        let ProxyState {
+            spoofer,
+            connector,
+            mitm,
+            upstream_resolver,
+            intercept_filter,
+            invalid_upstream_certificate,
+            client_sni,
+            stream_interceptor,
+            server_speaks_first_timeout,
+            proxy_authenticator,
+            ..
+        } = $this.into_state();
+        make_service_fn(move |conn: &AddrStream| {
             // While the state was moved into the make_service closure,
             // we need to clone it here because this closure is called
             // once for every connection.
             //
             // Each connection could send multiple requests, so
             // the `Service` needs a clone to handle later requests.
-            let ca = ca.clone();
+            let spoofer = spoofer.clone();
             let mitm = mitm.clone();
-            let additional_host_mapping = additional_host_mapping.clone();
-            let additional_root_certificates = additional_root_certificates.clone();
+            let connector = connector.clone();
+            let upstream_resolver = upstream_resolver.clone();
+            let intercept_filter = intercept_filter.clone();
+            let stream_interceptor = stream_interceptor.clone();
+            let proxy_authenticator = proxy_authenticator.clone();
+            let client_addr = conn.remote_addr();
 
             async move {
-                Ok::<_, Error>(service_fn(move |mut req: Request<Body>| {
+                Ok::<_, Error>(service_fn(move |mut req: Request<Body>| -> ResponseFuture {
                     log::info!("Received request to connect: {}", req.uri());
                     let mut res = Response::new(Body::empty());
 
-                    // The proxy can only handle CONNECT requests
+                    let principal = match &proxy_authenticator {
+                        Some(authenticator) => {
+                            match proxy_auth::authenticate(&mut req, &**authenticator) {
+                                Some(principal) => Some(principal),
+                                None => {
+                                    log::warn!("Proxy authentication failed for {}", client_addr);
+                                    return Box::pin(async { Ok(proxy_auth::challenge()) });
+                                }
+                            }
+                        }
+                        None => None,
+                    };
                     if req.method() == http::Method::CONNECT {
                         let target = target_host_port_from_connect(&req);
                         match target {
                             Ok((host, port)) => {
-                                let host = "odoh.cloudflare-dns.com".to_string();
-                                // TODO: handle non-encrypted proxying
+                                let upstream = upstream_resolver.resolve(&host, &port);
+                                let mut info = ConnectionInfo::new(
+                                    client_addr,
+                                    format!("{}:{}", host, port),
+                                    upstream,
+                                );
+                                info.principal = principal;
+                                let intercept =
+                                    intercept_filter.intercept(&host, &port, client_addr);
                                 // TODO: how to handle port != 80/443
-                                // In the case of a TLS tunnel request we spawn a new
-                                // service to handle the upgrade. This will only happen
-                                // after the currently running function finishes so we need
-                                // to spawn it as a separate future.
-                                let ca = ca.clone();
+                                let spoofer = spoofer.clone();
                                 let mitm = mitm.clone();
-                                let additional_host_mapping = additional_host_mapping.clone();
-                                let additional_root_certificates =
-                                    additional_root_certificates.clone();
-                                tokio::task::spawn(async move {
-                                    match hyper::upgrade::on(&mut req).await {
-                                        Ok(upgraded) => {
-                                            if let Err(e) = run_mitm_on_connection(
-                                                upgraded,
-                                                ca,
-                                                &host,
-                                                &port,
-                                                mitm,
-                                                additional_host_mapping.clone(),
-                                                additional_root_certificates.clone(),
-                                            )
-                                            .await
-                                            {
-                                                error!("Proxy failed: {}", e)
+                                let connector = connector.clone();
+                                let stream_interceptor = stream_interceptor.clone();
+                                return Box::pin(async move {
+                                    // Reach the upstream before accepting the tunnel so
+                                    // that failures can be reported with a 502
+                                    let tunnel = match open_tunnel(
+                                        &spoofer,
+                                        &connector,
+                                        invalid_upstream_certificate,
+                                        intercept,
+                                        info,
+                                    )
+                                    .await
+                                    {
+                                        Ok(tunnel) => tunnel,
+                                        Err(e) => {
+                                            error!("Proxy failed: {}", e);
+                                            *res.status_mut() =
+                                                http::status::StatusCode::BAD_GATEWAY;
+                                            *res.body_mut() = Body::from(describe(&e));
+                                            return Ok(res);
+                                        }
+                                    };
+                                    // In the case of a TLS tunnel request we spawn a new
+                                    // service to handle the upgrade. This will only happen
+                                    // after the currently running function finishes so we need
+                                    // to spawn it as a separate future.
+                                    tokio::task::spawn(async move {
+                                        match hyper::upgrade::on(&mut req).await {
+                                            Ok(upgraded) => {
+                                                let result = match tunnel {
+                                                    Tunnel::Intercepted(tunnel) => {
+                                                        run_mitm_on_connection(
+                                                            upgraded,
+                                                            *tunnel,
+                                                            mitm,
+                                                            connector,
+                                                            &spoofer,
+                                                            client_sni,
+                                                            stream_interceptor,
+                                                            server_speaks_first_timeout,
+                                                        )
+                                                        .await
+                                                    }
+                                                    Tunnel::Spliced(target_stream) => {
+                                                        splice(upgraded, target_stream).await
+                                                    }
+                                                };
+                                                if let Err(e) = result {
+                                                    error!("Proxy failed: {}", e)
+                                                }
+                                            }
+                                            Err(e) => {
+                                                error!("Failed to upgrade to TLS: {}", e);
+                                                tunnel.close(&connector);
                                             }
                                         }
-                                        Err(e) => error!("Failed to upgrade to TLS: {}", e),
-                                    }
+                                    });
+                                    *res.status_mut() = http::status::StatusCode::OK;
+                                    Ok(res)
                                 });
-                                *res.status_mut() = http::status::StatusCode::OK;
                             }
 
                             Err(e) => {
@@ -109,9 +206,41 @@ macro_rules! make_service {
                             }
                         }
                     } else {
-                        *res.status_mut() = http::status::StatusCode::BAD_REQUEST;
+                        // Anything else must be an absolute-form request for a
+                        // plain HTTP server, which is forwarded without a tunnel
+                        match target_host_port_from_absolute_uri(&req) {
+                            Ok((host, port)) => {
+                                let upstream = upstream_resolver.resolve(&host, &port);
+                                let mut info = ConnectionInfo::new(
+                                    client_addr,
+                                    format!("{}:{}", host, port),
+                                    upstream,
+                                );
+                                info.principal = principal;
+                                let mitm = mitm.clone();
+                                let connector = connector.clone();
+                                return Box::pin(async move {
+                                    match run_mitm_on_request(req, info, mitm, connector)
+                                        .await
+                                    {
+                                        Ok(response) => Ok(response),
+                                        Err(e) => {
+                                            error!("Proxy failed: {}", e);
+                                            *res.status_mut() =
+                                                http::status::StatusCode::BAD_GATEWAY;
+                                            *res.body_mut() = Body::from(describe(&e));
+                                            Ok(res)
+                                        }
+                                    }
+                                });
+                            }
+                            Err(e) => {
+                                error!("Bad request: unable to parse host from request: {}", e);
+                                *res.status_mut() = http::status::StatusCode::BAD_REQUEST;
+                            }
+                        }
                     }
-                    async move { Ok::<_, Error>(res) }
+                    Box::pin(async move { Ok::<_, Error>(res) })
                 }))
             }
         })
@@ -137,8 +266,27 @@ where
 {
     mitm_layer: T,
     ca: CertificateAuthority,
-    additional_root_certificates: Vec<Certificate>,
-    additional_host_mappings: HashMap<String, String>, // TODO: this should be more restrictively typed
+    additional_root_certificates: Vec<X509>,
+    upstream_tls_policy: UpstreamTlsPolicy,
+    invalid_upstream_certificate: InvalidUpstreamCertificate,
+    client_sni: ClientSni,
+    upstream_client_identities: HashMap<String, ClientIdentity>,
+    resolver: Arc<dyn Resolver>,
+    upstream_resolver: Arc<dyn UpstreamResolver>,
+    upstream_proxy: Option<UpstreamProxy>,
+    websocket_interceptor: Option<Arc<dyn WebSocketInterceptor>>,
+    stream_interceptor: Arc<dyn StreamInterceptor>,
+    server_speaks_first_timeout: Option<Duration>,
+    intercept_filter: Arc<dyn InterceptFilter>,
+    proxy_authenticator: Option<Arc<dyn ProxyAuthenticator>>,
+    socks5_credentials: HashMap<String, String>,
+    certificate_cache: Arc<CertificateCache>,
+    leaf_key_strategy: LeafKeyStrategy,
+    http2: bool,
+    max_pending_requests: usize,
+    pool_idle_timeout: Duration,
+    pool_max_idle_per_host: usize,
+    lazy_upstream_connection: bool,
 }
 
 /// Builder interface for constructing `MitmProxy`'s
@@ -155,8 +303,27 @@ where
 {
     mitm_layer: T,
     ca: CertificateAuthority,
-    additional_root_certificates: Vec<Certificate>,
-    additional_host_mappings: HashMap<String, String>,
+    additional_root_certificates: Vec<X509>,
+    upstream_tls_policy: UpstreamTlsPolicy,
+    invalid_upstream_certificate: InvalidUpstreamCertificate,
+    client_sni: ClientSni,
+    upstream_client_identities: HashMap<String, ClientIdentity>,
+    resolver: Arc<dyn Resolver>,
+    upstream_resolver: Arc<dyn UpstreamResolver>,
+    upstream_proxy: Option<UpstreamProxy>,
+    websocket_interceptor: Option<Arc<dyn WebSocketInterceptor>>,
+    stream_interceptor: Arc<dyn StreamInterceptor>,
+    server_speaks_first_timeout: Option<Duration>,
+    intercept_filter: Arc<dyn InterceptFilter>,
+    proxy_authenticator: Option<Arc<dyn ProxyAuthenticator>>,
+    socks5_credentials: HashMap<String, String>,
+    certificate_cache: Arc<CertificateCache>,
+    leaf_key_strategy: LeafKeyStrategy,
+    http2: bool,
+    max_pending_requests: usize,
+    pool_idle_timeout: Duration,
+    pool_max_idle_per_host: usize,
+    lazy_upstream_connection: bool,
 }
 
 // impl MitmProxyBuilder
@@ -176,27 +343,265 @@ where
             mitm_layer: self.mitm_layer,
             ca: self.ca,
             additional_root_certificates: self.additional_root_certificates,
-            additional_host_mappings: self.additional_host_mappings,
+            upstream_tls_policy: self.upstream_tls_policy,
+            invalid_upstream_certificate: self.invalid_upstream_certificate,
+            client_sni: self.client_sni,
+            upstream_client_identities: self.upstream_client_identities,
+            resolver: self.resolver,
+            upstream_resolver: self.upstream_resolver,
+            upstream_proxy: self.upstream_proxy,
+            websocket_interceptor: self.websocket_interceptor,
+            stream_interceptor: self.stream_interceptor,
+            server_speaks_first_timeout: self.server_speaks_first_timeout,
+            intercept_filter: self.intercept_filter,
+            proxy_authenticator: self.proxy_authenticator,
+            socks5_credentials: self.socks5_credentials,
+            certificate_cache: self.certificate_cache,
+            leaf_key_strategy: self.leaf_key_strategy,
+            http2: self.http2,
+            max_pending_requests: self.max_pending_requests,
+            pool_idle_timeout: self.pool_idle_timeout,
+            pool_max_idle_per_host: self.pool_max_idle_per_host,
+            lazy_upstream_connection: self.lazy_upstream_connection,
         }
     }
 
     /// Add root certificates that the proxy should trust when making outgoing
     /// connections. This is in addition to the system certificates that are
     /// already trusted.
-    pub fn additional_root_certificates(
+    #[must_use]
+    pub fn additional_root_certificates(mut self, additional_root_certificates: Vec<X509>) -> Self {
+        self.additional_root_certificates = additional_root_certificates;
+        self
+    }
+
+    /// Decide which upstream certificates to trust. Defaults to
+    /// `UpstreamTlsPolicy::Verify`; what was decided for a tunnel is recorded
+    /// in its `ConnectionInfo`.
+    #[must_use]
+    pub fn upstream_tls_policy(mut self, upstream_tls_policy: UpstreamTlsPolicy) -> Self {
+        self.upstream_tls_policy = upstream_tls_policy;
+        self
+    }
+
+    /// What to do when an upstream's certificate is not trusted under the
+    /// `UpstreamTlsPolicy`. By default the CONNECT is refused with a 502, but
+    /// the tunnel can instead show the client a deliberately untrusted
+    /// certificate so that the client makes its own decision.
+    #[must_use]
+    pub const fn invalid_upstream_certificate(
         mut self,
-        additional_root_certificates: Vec<Certificate>,
+        invalid_upstream_certificate: InvalidUpstreamCertificate,
     ) -> Self {
-        self.additional_root_certificates = additional_root_certificates;
+        self.invalid_upstream_certificate = invalid_upstream_certificate;
         self
     }
 
-    /// Add mappings for particular hosts to IP addresses. Useful for testing against local TLS servers.
+    /// Whether to read the server name from each intercepted client's
+    /// `ClientHello` and forge the certificate for that name, rather than the
+    /// upstream's, when the two differ. The CONNECT is answered before the
+    /// client sends its `ClientHello`, so with
+    /// `ClientSni::ForgeCertificateAndForward` the upstream connection made
+    /// for the CONNECT is given up and a new one made with the client's name.
+    /// Defaults to `ClientSni::Ignore`.
+    #[must_use]
+    pub const fn client_sni(mut self, client_sni: ClientSni) -> Self {
+        self.client_sni = client_sni;
+        self
+    }
+
+    /// Client certificates to present to upstreams that require mutual TLS,
+    /// keyed by the server name the upstream is reached with. Identities can
+    /// be loaded with `load_client_identity_from_pem_files` or
+    /// `ClientIdentity::from_pkcs12`.
+    #[must_use]
+    pub fn upstream_client_identities(
+        mut self,
+        upstream_client_identities: HashMap<String, ClientIdentity>,
+    ) -> Self {
+        self.upstream_client_identities = upstream_client_identities;
+        self
+    }
+
+    /// Map particular hosts to IP addresses, such as to test against local
+    /// TLS servers. This is shorthand for a `resolver` that is a
+    /// `StaticResolver` falling back to the system resolver, so it replaces
+    /// any resolver set before.
+    #[must_use]
     pub fn additional_host_mappings(
         mut self,
         additional_host_mappings: HashMap<String, String>,
     ) -> Self {
-        self.additional_host_mappings = additional_host_mappings;
+        self.resolver = Arc::new(StaticResolver::new(additional_host_mappings));
+        self
+    }
+
+    /// Choose how upstream host names are turned into addresses. Defaults to
+    /// the `SystemResolver`; `DohResolver` and, with the `odoh` feature,
+    /// `ObliviousDohResolver` keep the lookups private. Not used for
+    /// connections through an `upstream_proxy`, which resolves names itself.
+    #[must_use]
+    pub fn resolver<R: Resolver + 'static>(mut self, resolver: R) -> Self {
+        self.resolver = Arc::new(resolver);
+        self
+    }
+
+    /// Choose which upstream each CONNECT request is relayed to. By default
+    /// the proxy connects to the host and port the client asked for.
+    #[must_use]
+    pub fn upstream_resolver<R: UpstreamResolver + 'static>(
+        mut self,
+        upstream_resolver: R,
+    ) -> Self {
+        self.upstream_resolver = Arc::new(upstream_resolver);
+        self
+    }
+
+    /// Make every connection to an upstream through another proxy, unless the
+    /// `UpstreamResolver` picks a proxy of its own with `Upstream::with_proxy`.
+    /// Requests sent with `ThirdWheel::call` then go through the chain.
+    #[must_use]
+    pub fn upstream_proxy(mut self, upstream_proxy: UpstreamProxy) -> Self {
+        self.upstream_proxy = Some(upstream_proxy);
+        self
+    }
+
+    /// Hand the text and binary messages of WebSocket connections made through
+    /// the proxy to `websocket_interceptor`, which can inspect, modify, drop
+    /// or add to them. Without one, upgraded connections are spliced byte for
+    /// byte once the mitm layer has seen the upgrade response.
+    #[must_use]
+    pub fn websocket_interceptor<W: WebSocketInterceptor + 'static>(
+        mut self,
+        websocket_interceptor: W,
+    ) -> Self {
+        self.websocket_interceptor = Some(Arc::new(websocket_interceptor));
+        self
+    }
+
+    /// Hand intercepted tunnels that don't carry HTTP to `stream_interceptor`,
+    /// along with a new connection to the upstream. Whether a tunnel carries
+    /// HTTP is told from the protocol agreed with ALPN or, failing that, from
+    /// the first bytes the client sends. Clients that wait for the server to
+    /// speak first are recognised once `server_speaks_first_timeout` passes.
+    /// Defaults to `Splice`.
+    #[must_use]
+    pub fn stream_interceptor<I: StreamInterceptor + 'static>(
+        mut self,
+        stream_interceptor: I,
+    ) -> Self {
+        self.stream_interceptor = Arc::new(stream_interceptor);
+        self
+    }
+
+    /// Hand intercepted tunnels whose clients send nothing for `timeout` to the
+    /// `stream_interceptor`, taking them to be waiting for the server to speak
+    /// first, as in SMTP. Defaults to 500ms. An HTTP client slower than that to
+    /// send its first request is handed over too, and by default spliced
+    /// through to the upstream rather than intercepted. With `None` the proxy
+    /// waits for the client however long it takes, so protocols where the
+    /// server speaks first hang until the client gives up.
+    #[must_use]
+    pub const fn server_speaks_first_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.server_speaks_first_timeout = timeout;
+        self
+    }
+
+    /// Choose which CONNECT tunnels are intercepted. The rest are relayed to
+    /// their upstream untouched, without forging a certificate, which suits
+    /// hosts that pin their certificates or are out of scope. By default
+    /// every tunnel is intercepted.
+    #[must_use]
+    pub fn intercept_filter<F: InterceptFilter + 'static>(mut self, intercept_filter: F) -> Self {
+        self.intercept_filter = Arc::new(intercept_filter);
+        self
+    }
+
+    /// Require clients of the listeners from `bind` to authenticate with
+    /// `Proxy-Authorization: Basic` on their CONNECT and plain HTTP requests,
+    /// checking the credentials with `proxy_authenticator`. Clients that
+    /// don't are answered with a 407. Clients of `bind_socks5` must give
+    /// credentials it accepts with RFC 1929 username/password authentication,
+    /// in place of any `socks5_credentials`. The principal the client
+    /// authenticated as is put in the `ConnectionInfo` of its requests. By
+    /// default clients need not authenticate.
+    #[must_use]
+    pub fn proxy_authenticator<A: ProxyAuthenticator + 'static>(
+        mut self,
+        proxy_authenticator: A,
+    ) -> Self {
+        self.proxy_authenticator = Some(Arc::new(proxy_authenticator));
+        self
+    }
+
+    /// Usernames and their passwords, one of which SOCKS5 clients must give to
+    /// use the listener from `bind_socks5`. If empty, the default, SOCKS5
+    /// clients need not authenticate. Ignored if a `proxy_authenticator` is
+    /// set.
+    #[must_use]
+    pub fn socks5_credentials(mut self, socks5_credentials: HashMap<String, String>) -> Self {
+        self.socks5_credentials = socks5_credentials;
+        self
+    }
+
+    /// Share a cache of spoofed certificates between tunnels. Keep a clone of
+    /// the `Arc` to read its hit and miss counters while the proxy runs.
+    #[must_use]
+    pub fn certificate_cache(mut self, certificate_cache: Arc<CertificateCache>) -> Self {
+        self.certificate_cache = certificate_cache;
+        self
+    }
+
+    /// Choose the key pairs spoofed certificates are issued for. Defaults to a
+    /// single RSA-2048 key shared by every host.
+    #[must_use]
+    pub const fn leaf_key_strategy(mut self, leaf_key_strategy: LeafKeyStrategy) -> Self {
+        self.leaf_key_strategy = leaf_key_strategy;
+        self
+    }
+
+    /// Whether to speak HTTP/2 where the upstream server supports it. When
+    /// enabled, clients are only offered h2 if the upstream agreed to it.
+    /// Defaults to true.
+    #[must_use]
+    pub const fn http2(mut self, http2: bool) -> Self {
+        self.http2 = http2;
+        self
+    }
+
+    /// The most requests a tunnel may have waiting on its upstream connection
+    /// before `ThirdWheel::poll_ready` stops accepting more. Defaults to 32.
+    #[must_use]
+    pub const fn max_pending_requests(mut self, max_pending_requests: usize) -> Self {
+        self.max_pending_requests = max_pending_requests;
+        self
+    }
+
+    /// Keep upstream connections open after their tunnel closes so later
+    /// tunnels to the same upstream can skip the TCP and TLS handshakes.
+    /// Connections idle for longer than `idle_timeout` are closed, as are any
+    /// beyond `max_idle_per_host` for one upstream. A limit of zero disables
+    /// pooling. Defaults to 90 seconds and 4 connections.
+    #[must_use]
+    pub const fn connection_pool(
+        mut self,
+        idle_timeout: Duration,
+        max_idle_per_host: usize,
+    ) -> Self {
+        self.pool_idle_timeout = idle_timeout;
+        self.pool_max_idle_per_host = max_idle_per_host;
+        self
+    }
+
+    /// Wait for a tunnel's first request before connecting to the upstream,
+    /// so tunnels whose requests are all answered by the mitm layer never
+    /// reach the real server. Clients are then shown a certificate forged
+    /// from the host name alone, as
+    /// `create_signed_certificate_for_domain_with_key` does, rather than one
+    /// copied from the upstream's. Off by default.
+    #[must_use]
+    pub const fn lazy_upstream_connection(mut self, lazy: bool) -> Self {
+        self.lazy_upstream_connection = lazy;
         self
     }
 }
@@ -218,7 +623,62 @@ where
             mitm_layer,
             ca,
             additional_root_certificates: Vec::new(),
-            additional_host_mappings: HashMap::new(),
+            upstream_tls_policy: UpstreamTlsPolicy::default(),
+            invalid_upstream_certificate: InvalidUpstreamCertificate::default(),
+            client_sni: ClientSni::default(),
+            upstream_client_identities: HashMap::new(),
+            resolver: Arc::new(SystemResolver),
+            upstream_resolver: Arc::new(Passthrough),
+            upstream_proxy: None,
+            websocket_interceptor: None,
+            stream_interceptor: Arc::new(Splice),
+            server_speaks_first_timeout: Some(Duration::from_millis(500)),
+            intercept_filter: Arc::new(InterceptAll),
+            proxy_authenticator: None,
+            socks5_credentials: HashMap::new(),
+            certificate_cache: Arc::new(CertificateCache::default()),
+            leaf_key_strategy: LeafKeyStrategy::default(),
+            http2: true,
+            max_pending_requests: 32,
+            pool_idle_timeout: Duration::from_secs(90),
+            pool_max_idle_per_host: 4,
+            lazy_upstream_connection: false,
+        }
+    }
+
+    /// Build what every listener shares, consuming the configuration
+    fn into_state(self) -> ProxyState<T> {
+        let spoofer = Arc::new(CertificateSpoofer::new(
+            self.ca,
+            LeafKeys::new(self.leaf_key_strategy),
+            self.certificate_cache,
+        ));
+        let connector = Arc::new(Connector::new(
+            self.resolver,
+            self.upstream_proxy,
+            UpstreamTls {
+                connector: TlsConnector::new(self.additional_root_certificates),
+                policy: self.upstream_tls_policy,
+                client_identities: self.upstream_client_identities,
+            },
+            self.http2,
+            self.max_pending_requests,
+            ConnectionPool::new(self.pool_idle_timeout, self.pool_max_idle_per_host),
+            self.lazy_upstream_connection,
+            self.websocket_interceptor,
+        ));
+        ProxyState {
+            spoofer,
+            connector,
+            mitm: self.mitm_layer,
+            upstream_resolver: self.upstream_resolver,
+            intercept_filter: self.intercept_filter,
+            invalid_upstream_certificate: self.invalid_upstream_certificate,
+            client_sni: self.client_sni,
+            stream_interceptor: self.stream_interceptor,
+            server_speaks_first_timeout: self.server_speaks_first_timeout,
+            proxy_authenticator: self.proxy_authenticator,
+            socks5_credentials: Arc::new(self.socks5_credentials),
         }
     }
 
@@ -266,16 +726,299 @@ where
                 .map(|result| result.map_err(|e| e.into())),
         )
     }
+
+    /// Bind a listener for transparent proxying, where the firewall redirects
+    /// clients' HTTPS traffic to the proxy instead of the clients being
+    /// configured to use it, e.g. with
+    /// `iptables -t nat -A OUTPUT -p tcp --dport 443 -j REDIRECT --to-ports <port>`.
+    ///
+    /// Clients start their TLS handshake straight away, with no CONNECT
+    /// request. The upstream is the connection's original destination, found
+    /// with `SO_ORIGINAL_DST` on Linux, named by the server name from the
+    /// client's `ClientHello`. Failing either, the server name alone is used,
+    /// with port 443. Connections are then intercepted as CONNECT tunnels are.
+    ///
+    /// Both IPv4 and IPv6 are supported. IPv6 clients need the same rule added
+    /// with `ip6tables`, and the proxy bound to an IPv6 or dual-stack address.
+    ///
+    /// # Panics
+    /// If the address cannot be bound to, as `bind` does.
+    pub fn bind_transparent(
+        self,
+        addr: SocketAddr,
+    ) -> (SocketAddr, impl Future<Output = Result<(), Error>>) {
+        self.bind_transparent_with_graceful_shutdown(addr, futures::future::pending())
+    }
+
+    /// The same as `bind_transparent` except that the proxy stops accepting
+    /// connections once `signal` completes.
+    ///
+    /// # Panics
+    /// If the address cannot be bound to, as `bind` does.
+    pub fn bind_transparent_with_graceful_shutdown<F>(
+        self,
+        addr: SocketAddr,
+        signal: F,
+    ) -> (SocketAddr, impl Future<Output = Result<(), Error>>)
+    where
+        F: Future<Output = ()>,
+    {
+        bind_listener(
+            addr,
+            self.into_state(),
+            signal,
+            transparent::serve_transparent_connection,
+        )
+    }
+
+    /// Bind a SOCKS5 listener, for clients such as `curl --socks5-hostname`
+    /// that don't speak HTTP CONNECT. Only the CONNECT command is supported,
+    /// to domain names and IPv4 and IPv6 addresses, which are resolved and
+    /// intercepted as the targets of HTTP CONNECT requests are. Clients
+    /// authenticate if a `proxy_authenticator` or `socks5_credentials` are set.
+    ///
+    /// # Panics
+    /// If the address cannot be bound to, as `bind` does.
+    pub fn bind_socks5(
+        self,
+        addr: SocketAddr,
+    ) -> (SocketAddr, impl Future<Output = Result<(), Error>>) {
+        self.bind_socks5_with_graceful_shutdown(addr, futures::future::pending())
+    }
+
+    /// The same as `bind_socks5` except that the proxy stops accepting
+    /// connections once `signal` completes.
+    ///
+    /// # Panics
+    /// If the address cannot be bound to, as `bind` does.
+    pub fn bind_socks5_with_graceful_shutdown<F>(
+        self,
+        addr: SocketAddr,
+        signal: F,
+    ) -> (SocketAddr, impl Future<Output = Result<(), Error>>)
+    where
+        F: Future<Output = ()>,
+    {
+        bind_listener(
+            addr,
+            self.into_state(),
+            signal,
+            socks5::serve_socks5_connection,
+        )
+    }
+}
+
+/// Accept TCP connections on `addr` until `signal` completes, handing each to
+/// `serve` in a task of its own
+fn bind_listener<T, F, S, R>(
+    addr: SocketAddr,
+    state: ProxyState<T>,
+    signal: F,
+    serve: S,
+) -> (SocketAddr, impl Future<Output = Result<(), Error>>)
+where
+    T: Clone + Send + Sync + 'static,
+    F: Future<Output = ()>,
+    S: Fn(TcpStream, SocketAddr, ProxyState<T>) -> R,
+    R: Future<Output = Result<(), Error>> + Send + 'static,
+{
+    let listener = std::net::TcpListener::bind(addr)
+        .unwrap_or_else(|e| panic!("error binding to {}: {}", addr, e));
+    let local_addr = listener
+        .local_addr()
+        .unwrap_or_else(|e| panic!("error binding to {}: {}", addr, e));
+    let server = async move {
+        listener.set_nonblocking(true)?;
+        let listener = tokio::net::TcpListener::from_std(listener)?;
+        futures::pin_mut!(signal);
+        loop {
+            let (stream, client_addr) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        error!("Failed to accept connection: {e}");
+                        continue;
+                    }
+                },
+                () = &mut signal => return Ok(()),
+            };
+            let served = serve(stream, client_addr, state.clone());
+            tokio::task::spawn(async move {
+                if let Err(e) = served.await {
+                    error!("Proxy failed: {e}");
+                }
+            });
+        }
+    };
+    (local_addr, server)
 }
 
+/// Everything needed to serve a client connection, shared by all of them
+#[derive(Clone)]
+pub(crate) struct ProxyState<T> {
+    spoofer: Arc<CertificateSpoofer>,
+    connector: Arc<Connector>,
+    mitm: T,
+    upstream_resolver: Arc<dyn UpstreamResolver>,
+    intercept_filter: Arc<dyn InterceptFilter>,
+    invalid_upstream_certificate: InvalidUpstreamCertificate,
+    client_sni: ClientSni,
+    stream_interceptor: Arc<dyn StreamInterceptor>,
+    server_speaks_first_timeout: Option<Duration>,
+    proxy_authenticator: Option<Arc<dyn ProxyAuthenticator>>,
+    socks5_credentials: Arc<HashMap<String, String>>,
+}
+
+/// A CONNECT tunnel whose upstream side is ready, waiting for the client's
+/// side to be upgraded
+enum Tunnel {
+    Intercepted(Box<InterceptedTunnel>),
+    /// Not intercepted, so the client's bytes are copied to this connection
+    /// as they are
+    Spliced(TcpStream),
+}
+
+impl Tunnel {
+    fn close(self, connector: &Connector) {
+        if let Self::Intercepted(tunnel) = self {
+            if let Some(connection) = tunnel.connection {
+                connector.checkin(connection);
+            }
+        }
+    }
+}
+
+struct InterceptedTunnel {
+    /// What the client is shown in place of the upstream's certificate
+    identity: ServerIdentity,
+    /// The HTTP version to offer the client
+    version: Version,
+    third_wheel: ThirdWheel,
+    /// The upstream connection to check back in to the pool afterwards, unless
+    /// it will only be made on the first request
+    connection: Option<UpstreamConnection>,
+    info: ConnectionInfo,
+    /// Carried along for when the client's SNI calls for a new connection
+    invalid_upstream_certificate: InvalidUpstreamCertificate,
+}
+
+/// Connect to the upstream for a CONNECT request, unless the connection is to
+/// be lazy, and forge the certificate the client will be shown if the tunnel
+/// is to be intercepted
+async fn open_tunnel(
+    spoofer: &CertificateSpoofer,
+    connector: &Arc<Connector>,
+    invalid_upstream_certificate: InvalidUpstreamCertificate,
+    intercept: bool,
+    mut info: ConnectionInfo,
+) -> Result<Tunnel, Error> {
+    if !intercept {
+        return Ok(Tunnel::Spliced(connector.connect(&info.upstream).await?));
+    }
+
+    let upstream = info.upstream.clone();
+    info.sni = Some(upstream.sni.clone());
+
+    if connector.is_lazy() {
+        return Ok(Tunnel::Intercepted(Box::new(InterceptedTunnel {
+            identity: spoofer.identity_for_domain(&upstream.sni)?,
+            version: connector.preferred_version(),
+            third_wheel: connector.lazy_with_tls(&upstream),
+            connection: None,
+            info,
+            invalid_upstream_certificate,
+        })));
+    }
+
+    let (connection, identity) =
+        checkout_with_identity(spoofer, connector, &upstream, invalid_upstream_certificate).await?;
+    info.upstream_certificate
+        .clone_from(&connection.certificate);
+    info.upstream_verification = connection.verification;
+    Ok(Tunnel::Intercepted(Box::new(InterceptedTunnel {
+        identity,
+        version: connection.version,
+        third_wheel: connection.third_wheel.clone(),
+        connection: Some(connection),
+        info,
+        invalid_upstream_certificate,
+    })))
+}
+
+/// Check out a TLS connection to `upstream` along with the identity to show
+/// the client in its place. If the upstream isn't trusted and
+/// `invalid_upstream_certificate` allows, the connection is made without
+/// verification and its certificate mirrored.
+async fn checkout_with_identity(
+    spoofer: &CertificateSpoofer,
+    connector: &Arc<Connector>,
+    upstream: &Upstream,
+    invalid_upstream_certificate: InvalidUpstreamCertificate,
+) -> Result<(UpstreamConnection, ServerIdentity), Error> {
+    let connection = match connector.checkout_with_tls(upstream).await {
+        Ok(connection) => connection,
+        // Only failures to verify the certificate are retried, as a server
+        // that fails its handshake for any other reason isn't to be mirrored
+        Err(e)
+            if (tls::is_verification_failure(&e)
+                || matches!(e, Error::UpstreamCertificateRejected(_)))
+                && invalid_upstream_certificate != InvalidUpstreamCertificate::BadGateway =>
+        {
+            log::warn!(
+                "Certificate of {} not trusted, mirroring to client: {}",
+                upstream.sni,
+                e
+            );
+            connector
+                .checkout_unverified(upstream)
+                .await
+                .map_err(|_| e)?
+        }
+        Err(e) => return Err(e),
+    };
+    let identity = match connection.certificate.as_ref() {
+        None => Err(Error::ServerError(
+            "Server did not provide a certificate for TLS connection".to_string(),
+        )),
+        Some(certificate) => match connection.verification {
+            Some(UpstreamVerification::Failed)
+                if invalid_upstream_certificate == InvalidUpstreamCertificate::MirrorExpired =>
+            {
+                spoofer.expired_identity_for(certificate)
+            }
+            Some(UpstreamVerification::Failed) => spoofer.self_signed_identity_for(certificate),
+            _ => spoofer.identity_for(&upstream.sni, certificate),
+        },
+    };
+    match identity {
+        Ok(identity) => Ok((connection, identity)),
+        Err(e) => {
+            connector.checkin(connection);
+            Err(e)
+        }
+    }
+}
+
+/// Copy bytes both ways between the client and the upstream until both sides
+/// are closed
+async fn splice<S>(mut upgraded: S, mut target_stream: TcpStream) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    tokio::io::copy_bidirectional(&mut upgraded, &mut target_stream).await?;
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn run_mitm_on_connection<S, T, U>(
     upgraded: S,
-    ca: Arc<CertificateAuthority>,
-    host: &str,
-    port: &str,
+    mut tunnel: InterceptedTunnel,
     mitm_maker: T,
-    additional_host_mapping: HashMap<String, String>,
-    additional_root_certificates: Vec<Certificate>,
+    connector: Arc<Connector>,
+    spoofer: &CertificateSpoofer,
+    client_sni: ClientSni,
+    stream_interceptor: Arc<dyn StreamInterceptor>,
+    server_speaks_first_timeout: Option<Duration>,
 ) -> Result<(), Error>
 where
     T: Layer<ThirdWheel, Service = U> + Sync + std::marker::Send + 'static + Clone,
@@ -288,70 +1031,220 @@ where
     U::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
     <U as Service<Request<Body>>>::Future: Send,
 {
-    let (target_stream, target_certificate) = connect_to_target_with_tls(
-        host,
-        port,
-        additional_host_mapping,
-        additional_root_certificates,
+    let upgraded = if client_sni == ClientSni::Ignore {
+        Rewind::new(upgraded, Vec::new())
+    } else {
+        let (server_name, upgraded) = match read_server_name(upgraded).await {
+            Ok(read) => read,
+            Err(e) => {
+                if let Some(connection) = tunnel.connection {
+                    connector.checkin(connection);
+                }
+                return Err(e);
+            }
+        };
+        tunnel.info.client_sni.clone_from(&server_name);
+        if let Some(server_name) = server_name.filter(|name| *name != tunnel.info.upstream.sni) {
+            tunnel =
+                follow_client_sni(tunnel, server_name, client_sni, spoofer, &connector).await?;
+        }
+        upgraded
+    };
+    let InterceptedTunnel {
+        identity,
+        version,
+        third_wheel,
+        connection,
+        info,
+        ..
+    } = tunnel;
+    let served = async {
+        let (client_stream, protocol) =
+            tls::accept(upgraded, identity, alpn_protocols_for(version)).await?;
+        // Agreeing on a protocol with ALPN means agreeing on a HTTP version
+        let (is_http, client_stream) = if protocol.is_some() {
+            (true, Rewind::new(client_stream, Vec::new()))
+        } else {
+            sniff_http(client_stream, server_speaks_first_timeout).await?
+        };
+        if is_http {
+            serve_client(
+                client_stream,
+                negotiated_version(protocol),
+                third_wheel,
+                mitm_maker,
+                info,
+            )
+            .await
+        } else {
+            let verify = info.upstream_verification != Some(UpstreamVerification::Failed);
+            let upstream_stream = connector.connect_raw(&info.upstream, verify).await?;
+            stream_interceptor
+                .intercept(Box::new(client_stream), Box::new(upstream_stream), info)
+                .await
+        }
+    }
+    .await;
+    if let Some(connection) = connection {
+        connector.checkin(connection);
+    }
+    served
+}
+
+/// Rework `tunnel` for the server name the client asked for in its
+/// `ClientHello`, which differs from the upstream's
+async fn follow_client_sni(
+    mut tunnel: InterceptedTunnel,
+    server_name: String,
+    client_sni: ClientSni,
+    spoofer: &CertificateSpoofer,
+    connector: &Arc<Connector>,
+) -> Result<InterceptedTunnel, Error> {
+    if client_sni != ClientSni::ForgeCertificateAndForward {
+        // A mirrored certificate must stay as untrusted as the upstream's was
+        if tunnel.info.upstream_verification != Some(UpstreamVerification::Failed) {
+            tunnel.identity = spoofer.identity_for_domain(&server_name)?;
+        }
+        return Ok(tunnel);
+    }
+
+    // Start over with an upstream connection made with the client's name
+    if let Some(connection) = tunnel.connection.take() {
+        connector.checkin(connection);
+    }
+    let mut upstream = tunnel.info.upstream.clone();
+    upstream.sni = server_name;
+    tunnel.info.sni = Some(upstream.sni.clone());
+    if connector.is_lazy() {
+        tunnel.identity = spoofer.identity_for_domain(&upstream.sni)?;
+        tunnel.third_wheel = connector.lazy_with_tls(&upstream);
+        tunnel.info.upstream = upstream;
+        return Ok(tunnel);
+    }
+
+    let (connection, identity) = checkout_with_identity(
+        spoofer,
+        connector,
+        &upstream,
+        tunnel.invalid_upstream_certificate,
     )
     .await?;
-    let certificate = spoof_certificate(&target_certificate, &ca)?;
-    let identity = native_identity(&certificate, &ca.key)?;
-    let client = TlsAcceptor::from(native_tls::TlsAcceptor::new(identity)?);
-    let client_stream = client.accept(upgraded).await?;
-
-    let (request_sender, connection) = Builder::new()
-        .handshake::<TlsStream<TcpStream>, Body>(target_stream)
-        .await?;
-    tokio::spawn(connection);
-    let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
-    tokio::spawn(async move {
-        RequestSendingSynchronizer::new(request_sender, receiver)
-            .run()
-            .await
-    });
-    let third_wheel = ThirdWheel::new(sender);
-    let mitm_layer = mitm_maker.layer(third_wheel);
+    tunnel.identity = identity;
+    tunnel.version = connection.version;
+    tunnel.third_wheel = connection.third_wheel.clone();
+    tunnel.info.upstream = upstream;
+    tunnel
+        .info
+        .upstream_certificate
+        .clone_from(&connection.certificate);
+    tunnel.info.upstream_verification = connection.verification;
+    tunnel.connection = Some(connection);
+    Ok(tunnel)
+}
+
+/// Serve the requests the client sends over its decrypted stream through the
+/// mitm layer, tagged with `info`
+async fn serve_client<S, T, U>(
+    client_stream: S,
+    client_version: Version,
+    third_wheel: ThirdWheel,
+    mitm_maker: T,
+    info: ConnectionInfo,
+) -> Result<(), Error>
+where
+    T: Layer<ThirdWheel, Service = U> + Sync + std::marker::Send + 'static + Clone,
+    S: AsyncRead + AsyncWrite + Unpin + 'static + Send,
+    U: Service<Request<Body>, Response = <ThirdWheel as Service<Request<Body>>>::Response>
+        + Sync
+        + Send
+        + 'static
+        + Clone,
+    U::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    <U as Service<Request<Body>>>::Future: Send,
+{
+    let mitm_layer = WithConnectionInfo::new(mitm_maker.layer(third_wheel), info);
 
     Http::new()
+        .http2_only(client_version == Version::HTTP_2)
         .serve_connection(client_stream, mitm_layer)
+        .with_upgrades()
         .await
-        .map_err(|err| err.into())
+        .map_err(Into::into)
 }
 
-async fn connect_to_target_with_tls(
-    host: &str,
-    port: &str,
-    additional_host_mapping: HashMap<String, String>,
-    additional_root_certificates: Vec<Certificate>,
-) -> Result<(TlsStream<TcpStream>, X509), Error> {
-    let host_address = additional_host_mapping
-        .get(host)
-        .map_or(host, std::string::String::as_str);
-    let target_stream = TcpStream::connect(format!("{}:{}", host_address, port)).await?;
-
-    let mut connector = native_tls::TlsConnector::builder();
-    for root_certificate in additional_root_certificates {
-        connector.add_root_certificate(root_certificate);
-    }
-    let connector = connector.build()?;
-
-    let tokio_connector = tokio_native_tls::TlsConnector::from(connector);
-    let target_stream = tokio_connector.connect(host, target_stream).await?;
-    // TODO: Currently to copy the certificate we do a round trip from one library -> der -> other library. This is inefficient, it should be possible to do it better some how.
-    let certificate = &target_stream.get_ref().peer_certificate()?;
-
-    let certificate = match certificate {
-        Some(cert) => cert,
-        None => {
-            return Err(Error::ServerError(
-                "Server did not provide a certificate for TLS connection".to_string(),
-            ))
-        }
+async fn run_mitm_on_request<T, U>(
+    mut request: Request<Body>,
+    info: ConnectionInfo,
+    mitm_maker: T,
+    connector: Arc<Connector>,
+) -> Result<Response<Body>, Error>
+where
+    T: Layer<ThirdWheel, Service = U> + Sync + std::marker::Send + 'static + Clone,
+    U: Service<Request<Body>, Response = <ThirdWheel as Service<Request<Body>>>::Response>
+        + Sync
+        + Send
+        + 'static
+        + Clone,
+    U::Error: std::error::Error,
+    <U as Service<Request<Body>>>::Future: Send,
+{
+    let connection = connector.checkout(&info.upstream).await?;
+    request.extensions_mut().insert(info);
+    let mut mitm_layer = mitm_maker.layer(connection.third_wheel.clone());
+    let checkin = Checkin {
+        connector,
+        connection: Some(connection),
     };
-    let certificate = openssl::x509::X509::from_der(&certificate.to_der()?)?;
 
-    Ok((target_stream, certificate))
+    let response = async {
+        futures::future::poll_fn(|cx| mitm_layer.poll_ready(cx))
+            .await
+            .map_err(|e| Error::ServerError(e.to_string()))?;
+        mitm_layer
+            .call(request)
+            .await
+            .map_err(|e| Error::ServerError(e.to_string()))
+    }
+    .await?;
+
+    // The body may still be streaming on the connection, so it only goes back
+    // to the pool once the body is done with
+    let (parts, body) = response.into_parts();
+    let body = Body::wrap_stream(body.inspect(move |_| {
+        let _ = &checkin;
+    }));
+    Ok(Response::from_parts(parts, body))
+}
+
+/// Returns an upstream connection to the pool when dropped
+struct Checkin {
+    connector: Arc<Connector>,
+    connection: Option<UpstreamConnection>,
+}
+
+impl Drop for Checkin {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            self.connector.checkin(connection);
+        }
+    }
+}
+
+/// `e` and everything that caused it, for telling clients why the upstream
+/// couldn't be reached, down to the reason a certificate failed verification
+fn describe(e: &Error) -> String {
+    let mut description = e.to_string();
+    let mut source = std::error::Error::source(e);
+    while let Some(cause) = source {
+        let message = cause.to_string();
+        // Transparent errors, and some TLS errors, repeat their source's message
+        if !description.contains(&message) {
+            description.push_str(": ");
+            description.push_str(&message);
+        }
+        source = cause.source();
+    }
+    description
 }
 
 fn target_host_port_from_connect(request: &Request<Body>) -> Result<(String, String), Error> {
@@ -367,3 +1260,21 @@ fn target_host_port_from_connect(request: &Request<Body>) -> Result<(String, Str
         .ok_or_else(|| Error::RequestError("No port found on CONNECT request".to_string()))?;
     Ok((host, port))
 }
+
+fn target_host_port_from_absolute_uri(request: &Request<Body>) -> Result<(String, String), Error> {
+    if request.uri().scheme() != Some(&http::uri::Scheme::HTTP) {
+        return Err(Error::RequestError(
+            "Only absolute http:// URIs can be proxied without CONNECT".to_string(),
+        ));
+    }
+    let host = request
+        .uri()
+        .host()
+        .map(std::string::ToString::to_string)
+        .ok_or_else(|| Error::RequestError("No host found on request".to_string()))?;
+    let port = request
+        .uri()
+        .port()
+        .map_or_else(|| "80".to_string(), |x| x.to_string());
+    Ok((host, port))
+}
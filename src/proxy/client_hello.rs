@@ -0,0 +1,145 @@
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::TcpStream;
+
+use super::reader::Reader;
+use super::rewind::Rewind;
+use crate::error::Error;
+
+/// Enough for a TLS record header and the largest record it can announce
+const MAX_CLIENT_HELLO_LENGTH: usize = 5 + (1 << 14);
+/// How often and for how long to wait for the rest of a `ClientHello` that has
+/// only partly arrived
+const PEEK_INTERVAL: Duration = Duration::from_millis(10);
+const MAX_PEEKS: usize = 100;
+
+/// What the proxy does when the server name in the `ClientHello` of an
+/// intercepted tunnel differs from the upstream's, as when a client fronts one
+/// domain with another
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ClientSni {
+    /// Show the client the certificate forged for the upstream, whatever name
+    /// it asked for
+    #[default]
+    Ignore,
+    /// Show the client a certificate forged, or taken from the cache, for the
+    /// exact name it asked for. The upstream is still reached with its own
+    /// server name.
+    ForgeCertificate,
+    /// As `ForgeCertificate`, and also reconnect to the upstream with the
+    /// client's server name, so the upstream sees what the client sent
+    ForgeCertificateAndForward,
+}
+
+/// What can be told from the first bytes a client sent
+#[derive(Debug)]
+enum ClientHello {
+    /// The record holding the `ClientHello` has not all arrived yet
+    Incomplete,
+    /// The bytes are not a TLS handshake
+    NotTls,
+    /// The `ClientHello`, and the server name it asked for, if any
+    ServerName(Option<String>),
+}
+
+/// Read the SNI from the `ClientHello` a client is about to send, leaving the
+/// bytes in the socket for the TLS handshake proper
+pub(crate) async fn peek_server_name(stream: &TcpStream) -> Option<String> {
+    let mut buffer = vec![0; MAX_CLIENT_HELLO_LENGTH];
+    for _ in 0..MAX_PEEKS {
+        let length = stream.peek(&mut buffer).await.ok()?;
+        if length == 0 {
+            return None;
+        }
+        match parse_client_hello(&buffer[..length]) {
+            ClientHello::Incomplete => tokio::time::sleep(PEEK_INTERVAL).await,
+            ClientHello::NotTls => return None,
+            ClientHello::ServerName(server_name) => return server_name,
+        }
+    }
+    None
+}
+
+/// Read the `ClientHello` a client starts its handshake with for the server
+/// name it asks for. Returns the name and the stream with what was read put
+/// back for the handshake proper.
+pub(crate) async fn read_server_name<S>(mut stream: S) -> Result<(Option<String>, Rewind<S>), Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 4096];
+    let server_name = loop {
+        match parse_client_hello(&buffer) {
+            ClientHello::Incomplete if buffer.len() < MAX_CLIENT_HELLO_LENGTH => {}
+            ClientHello::ServerName(server_name) => break server_name,
+            ClientHello::Incomplete | ClientHello::NotTls => break None,
+        }
+        let length = stream.read(&mut chunk).await?;
+        if length == 0 {
+            break None;
+        }
+        buffer.extend_from_slice(&chunk[..length]);
+    };
+    Ok((server_name, Rewind::new(stream, buffer)))
+}
+
+fn parse_client_hello(bytes: &[u8]) -> ClientHello {
+    const HANDSHAKE_RECORD: u8 = 22;
+    if bytes.is_empty() {
+        return ClientHello::Incomplete;
+    }
+    if bytes[0] != HANDSHAKE_RECORD {
+        return ClientHello::NotTls;
+    }
+    if bytes.len() < 5 {
+        return ClientHello::Incomplete;
+    }
+    let record_length = usize::from(u16::from_be_bytes([bytes[3], bytes[4]]));
+    // A ClientHello too long for one record is not worth reassembling just for
+    // the server name
+    bytes
+        .get(5..5 + record_length)
+        .map_or(ClientHello::Incomplete, |record| {
+            ClientHello::ServerName(server_name(&mut Reader(record)))
+        })
+}
+
+fn server_name(record: &mut Reader<'_>) -> Option<String> {
+    const CLIENT_HELLO: u8 = 1;
+    const SERVER_NAME_EXTENSION: u16 = 0;
+    const HOST_NAME: u8 = 0;
+
+    if record.u8()? != CLIENT_HELLO {
+        return None;
+    }
+    record.take(3)?; // handshake length
+    record.take(2 + 32)?; // client version and random
+    let session_id_length = record.u8()?;
+    record.take(usize::from(session_id_length))?;
+    let cipher_suites_length = record.u16()?;
+    record.take(usize::from(cipher_suites_length))?;
+    let compression_methods_length = record.u8()?;
+    record.take(usize::from(compression_methods_length))?;
+
+    let extensions_length = record.u16()?;
+    let mut extensions = Reader(record.take(usize::from(extensions_length))?);
+    while let Some(extension_type) = extensions.u16() {
+        let data_length = extensions.u16()?;
+        let mut extension_data = Reader(extensions.take(usize::from(data_length))?);
+        if extension_type != SERVER_NAME_EXTENSION {
+            continue;
+        }
+        let list_length = extension_data.u16()?;
+        let mut names = Reader(extension_data.take(usize::from(list_length))?);
+        while let Some(name_type) = names.u8() {
+            let name_length = names.u16()?;
+            let name = names.take(usize::from(name_length))?;
+            if name_type == HOST_NAME {
+                return String::from_utf8(name.to_vec()).ok();
+            }
+        }
+    }
+    None
+}
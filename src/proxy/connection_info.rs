@@ -0,0 +1,91 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+
+use http::Request;
+use hyper::{service::Service, Body};
+use openssl::x509::X509;
+
+use super::{tls_policy::UpstreamVerification, upstream::Upstream};
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+/// What the proxy knows about the tunnel a request arrived on
+///
+/// A copy is inserted into the extensions of every request handed to the mitm
+/// layer, so it can be read with `request.extensions().get::<ConnectionInfo>()`.
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    /// Unique within the process, shared by every request on one tunnel
+    pub id: u64,
+    /// The address the client connected to the proxy from
+    pub client_addr: SocketAddr,
+    /// The `host:port` the client asked for, from the CONNECT request or the
+    /// absolute URI of a plain HTTP request
+    pub authority: String,
+    /// Where the proxy sends the requests, after any `UpstreamResolver`
+    pub upstream: Upstream,
+    /// The server name used on the upstream TLS handshake and, unless
+    /// `ClientSni` says otherwise, in the spoofed certificate. `None` for
+    /// plain HTTP.
+    pub sni: Option<String>,
+    /// The server name the client asked for in its `ClientHello`. Only read
+    /// when the proxy's `ClientSni` is not `Ignore`.
+    pub client_sni: Option<String>,
+    /// The certificate the upstream presented. `None` for plain HTTP and for
+    /// tunnels that connect lazily.
+    pub upstream_certificate: Option<X509>,
+    /// How `upstream_certificate` came to be trusted under the proxy's
+    /// `UpstreamTlsPolicy`
+    pub upstream_verification: Option<UpstreamVerification>,
+    /// Who the client authenticated to the proxy as, with `Proxy-Authorization`
+    /// or SOCKS5. `None` if the proxy doesn't ask clients to authenticate.
+    pub principal: Option<String>,
+}
+
+impl ConnectionInfo {
+    pub(crate) fn new(client_addr: SocketAddr, authority: String, upstream: Upstream) -> Self {
+        Self {
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            client_addr,
+            authority,
+            upstream,
+            sni: None,
+            client_sni: None,
+            upstream_certificate: None,
+            upstream_verification: None,
+            principal: None,
+        }
+    }
+}
+
+/// Inserts a `ConnectionInfo` into each request before passing it on
+#[derive(Clone)]
+pub(crate) struct WithConnectionInfo<S> {
+    inner: S,
+    info: ConnectionInfo,
+}
+
+impl<S> WithConnectionInfo<S> {
+    pub(crate) const fn new(inner: S, info: ConnectionInfo) -> Self {
+        Self { inner, info }
+    }
+}
+
+impl<S> Service<Request<Body>> for WithConnectionInfo<S>
+where
+    S: Service<Request<Body>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request<Body>) -> Self::Future {
+        request.extensions_mut().insert(self.info.clone());
+        self.inner.call(request)
+    }
+}
@@ -0,0 +1,349 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use http::Version;
+use hyper::{client::conn::Builder, Body};
+use openssl::x509::X509;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+use crate::certificates::ClientIdentity;
+use crate::error::Error;
+use crate::tls::{ClientTlsStream, TlsConnector};
+
+use super::{
+    mitm::ThirdWheel,
+    pool::{ConnectionPool, PoolKey, UpstreamConnection},
+    resolver::Resolver,
+    tls_policy::{UpstreamTlsPolicy, UpstreamVerification},
+    upstream::Upstream,
+    upstream_proxy::UpstreamProxy,
+    websocket::WebSocketInterceptor,
+};
+
+const H2: &str = "h2";
+const HTTP_1_1: &str = "http/1.1";
+
+/// How to set up TLS with upstreams
+pub(crate) struct UpstreamTls {
+    pub(crate) connector: TlsConnector,
+    pub(crate) policy: UpstreamTlsPolicy,
+    /// Identities to present to upstreams that ask for a client certificate,
+    /// keyed by server name
+    pub(crate) client_identities: HashMap<String, ClientIdentity>,
+}
+
+/// Makes the proxy's connections to upstream servers
+pub(crate) struct Connector {
+    resolver: Arc<dyn Resolver>,
+    upstream_proxy: Option<UpstreamProxy>,
+    tls: UpstreamTls,
+    http2: bool,
+    max_pending_requests: usize,
+    pool: ConnectionPool,
+    lazy: bool,
+    websocket_interceptor: Option<Arc<dyn WebSocketInterceptor>>,
+}
+
+impl Connector {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) const fn new(
+        resolver: Arc<dyn Resolver>,
+        upstream_proxy: Option<UpstreamProxy>,
+        tls: UpstreamTls,
+        http2: bool,
+        max_pending_requests: usize,
+        pool: ConnectionPool,
+        lazy: bool,
+        websocket_interceptor: Option<Arc<dyn WebSocketInterceptor>>,
+    ) -> Self {
+        Self {
+            resolver,
+            upstream_proxy,
+            tls,
+            http2,
+            max_pending_requests,
+            pool,
+            lazy,
+            websocket_interceptor,
+        }
+    }
+
+    /// Whether tunnels should wait for their first request before connecting
+    /// to the upstream
+    pub(crate) const fn is_lazy(&self) -> bool {
+        self.lazy
+    }
+
+    /// What to hand the messages of upgraded WebSocket connections to
+    pub(crate) fn websocket_interceptor(&self) -> Option<Arc<dyn WebSocketInterceptor>> {
+        self.websocket_interceptor.clone()
+    }
+
+    /// The HTTP version to offer clients before the upstream's is known
+    pub(crate) const fn preferred_version(&self) -> Version {
+        if self.http2 {
+            Version::HTTP_2
+        } else {
+            Version::HTTP_11
+        }
+    }
+
+    /// A `ThirdWheel` that checks out a TLS connection to the upstream on its
+    /// first request and checks it back in once the tunnel is done with it
+    pub(crate) fn lazy_with_tls(self: &Arc<Self>, upstream: &Upstream) -> ThirdWheel {
+        let connector = Arc::clone(self);
+        let releaser = Arc::clone(self);
+        let upstream = upstream.clone();
+        ThirdWheel::lazy(
+            async move { connector.checkout_with_tls(&upstream).await },
+            move |connection| releaser.checkin(connection),
+            self.max_pending_requests,
+            Arc::downgrade(self),
+        )
+    }
+
+    /// A HTTP/1.1 client on a plain TCP connection to the upstream, reusing
+    /// an idle pooled connection if there is one
+    pub(crate) async fn checkout(
+        self: &Arc<Self>,
+        upstream: &Upstream,
+    ) -> Result<UpstreamConnection, Error> {
+        let key = PoolKey {
+            upstream: upstream.clone(),
+            tls: false,
+        };
+        if let Some(connection) = self.pool.checkout(&key) {
+            return Ok(connection);
+        }
+        let target_stream = self.connect(upstream).await?;
+        let third_wheel = self
+            .handshake(target_stream, &key, Version::HTTP_11)
+            .await?;
+        Ok(UpstreamConnection {
+            key,
+            third_wheel,
+            certificate: None,
+            verification: None,
+            version: Version::HTTP_11,
+        })
+    }
+
+    /// A HTTP client on a TLS connection to the upstream, reusing an idle
+    /// pooled connection if there is one
+    pub(crate) async fn checkout_with_tls(
+        self: &Arc<Self>,
+        upstream: &Upstream,
+    ) -> Result<UpstreamConnection, Error> {
+        let key = PoolKey {
+            upstream: upstream.clone(),
+            tls: true,
+        };
+        if let Some(connection) = self.pool.checkout(&key) {
+            return Ok(connection);
+        }
+        let (target_stream, certificate, verification, version) =
+            self.connect_with_tls(upstream).await?;
+        let third_wheel = self.handshake(target_stream, &key, version).await?;
+        Ok(UpstreamConnection {
+            key,
+            third_wheel,
+            certificate: Some(certificate),
+            verification: Some(verification),
+            version,
+        })
+    }
+
+    /// A HTTP client on a TLS connection to the upstream whose certificate is
+    /// not checked at all, for tunnels that mirror an invalid certificate to
+    /// the client. These connections are never pooled.
+    pub(crate) async fn checkout_unverified(
+        self: &Arc<Self>,
+        upstream: &Upstream,
+    ) -> Result<UpstreamConnection, Error> {
+        let key = PoolKey {
+            upstream: upstream.clone(),
+            tls: true,
+        };
+        let (target_stream, certificate, version) = self
+            .tls_handshake(upstream, false, self.alpn_protocols())
+            .await?;
+        let third_wheel = self.handshake(target_stream, &key, version).await?;
+        Ok(UpstreamConnection {
+            key,
+            third_wheel,
+            certificate: Some(certificate),
+            verification: Some(UpstreamVerification::Failed),
+            version,
+        })
+    }
+
+    /// A HTTP/1.1 client on a new connection to the upstream, for requests
+    /// asking for an upgrade, which HTTP/2 connections can't carry. These
+    /// connections are never pooled, as an upgrade takes them over.
+    pub(crate) async fn connect_for_upgrade(
+        self: &Arc<Self>,
+        key: &PoolKey,
+    ) -> Result<ThirdWheel, Error> {
+        let upstream = &key.upstream;
+        if !key.tls {
+            let target_stream = self.connect(upstream).await?;
+            return self.handshake(target_stream, key, Version::HTTP_11).await;
+        }
+        let verify_chain = self.tls.policy.verifies_chain(&upstream.sni);
+        let (target_stream, certificate, _) = self
+            .tls_handshake(upstream, verify_chain, &[HTTP_1_1])
+            .await?;
+        self.tls.policy.check(&upstream.sni, &certificate)?;
+        self.handshake(target_stream, key, Version::HTTP_11).await
+    }
+
+    /// A TLS connection to the upstream for a tunnel that doesn't carry HTTP,
+    /// offering no protocols with ALPN. The upstream's certificate is only
+    /// checked if `verify` is set, as it isn't for tunnels that mirror an
+    /// invalid certificate to the client.
+    pub(crate) async fn connect_raw(
+        &self,
+        upstream: &Upstream,
+        verify: bool,
+    ) -> Result<ClientTlsStream<TcpStream>, Error> {
+        let verify_chain = verify && self.tls.policy.verifies_chain(&upstream.sni);
+        let (target_stream, certificate, _) =
+            self.tls_handshake(upstream, verify_chain, &[]).await?;
+        if verify {
+            self.tls.policy.check(&upstream.sni, &certificate)?;
+        }
+        Ok(target_stream)
+    }
+
+    /// Hand a connection back for reuse once nothing is using it
+    pub(crate) fn checkin(&self, connection: UpstreamConnection) {
+        self.pool.checkin(connection);
+    }
+
+    /// Open a plain TCP connection to the upstream, through the upstream
+    /// proxy if there is one. The `Resolver` is not used for connections
+    /// through a proxy, which resolves host names itself.
+    pub(crate) async fn connect(&self, upstream: &Upstream) -> Result<TcpStream, Error> {
+        if let Some(proxy) = upstream.proxy.as_ref().or(self.upstream_proxy.as_ref()) {
+            return proxy.connect(&upstream.host, &upstream.port).await;
+        }
+        let port: u16 = upstream
+            .port
+            .parse()
+            .map_err(|_| Error::RequestError(format!("Invalid port {}", upstream.port)))?;
+        let host = upstream.host.trim_start_matches('[').trim_end_matches(']');
+        let addresses = match host.parse::<IpAddr>() {
+            Ok(address) => vec![address],
+            Err(_) => self.resolver.resolve(host).await?,
+        };
+        if addresses.is_empty() {
+            return Err(Error::DnsError(format!("{host} has no addresses")));
+        }
+        let addresses: Vec<SocketAddr> = addresses
+            .into_iter()
+            .map(|address| SocketAddr::new(address, port))
+            .collect();
+        Ok(TcpStream::connect(&addresses[..]).await?)
+    }
+
+    /// Open a TLS connection to the upstream, checking its certificate with the
+    /// `UpstreamTlsPolicy`. Returns the stream, the server's certificate, how
+    /// it was trusted and the HTTP version agreed with ALPN.
+    pub(crate) async fn connect_with_tls(
+        &self,
+        upstream: &Upstream,
+    ) -> Result<
+        (
+            ClientTlsStream<TcpStream>,
+            X509,
+            UpstreamVerification,
+            Version,
+        ),
+        Error,
+    > {
+        let verify_chain = self.tls.policy.verifies_chain(&upstream.sni);
+        let (target_stream, certificate, version) = self
+            .tls_handshake(upstream, verify_chain, self.alpn_protocols())
+            .await?;
+        let verification = self.tls.policy.check(&upstream.sni, &certificate)?;
+        Ok((target_stream, certificate, verification, version))
+    }
+
+    /// The protocols to offer upstreams
+    const fn alpn_protocols(&self) -> &'static [&'static str] {
+        if self.http2 {
+            &[H2, HTTP_1_1]
+        } else {
+            &[HTTP_1_1]
+        }
+    }
+
+    /// Open a TLS connection to the upstream, offering the `alpn` protocols and
+    /// verifying its certificate chain only if `verify_chain` is set
+    async fn tls_handshake(
+        &self,
+        upstream: &Upstream,
+        verify_chain: bool,
+        alpn: &[&str],
+    ) -> Result<(ClientTlsStream<TcpStream>, X509, Version), Error> {
+        let target_stream = self.connect(upstream).await?;
+
+        let (target_stream, certificate, protocol) = self
+            .tls
+            .connector
+            .connect(
+                target_stream,
+                &upstream.sni,
+                self.tls.client_identities.get(&upstream.sni),
+                verify_chain,
+                alpn,
+            )
+            .await?;
+        let version = negotiated_version(protocol);
+        Ok((target_stream, certificate, version))
+    }
+
+    /// Start a HTTP client of the given version on the connection to `key`'s
+    /// upstream and hand it to a `ThirdWheel` for sending requests on
+    async fn handshake<S>(
+        self: &Arc<Self>,
+        target_stream: S,
+        key: &PoolKey,
+        version: Version,
+    ) -> Result<ThirdWheel, Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (request_sender, connection) = Builder::new()
+            .http2_only(version == Version::HTTP_2)
+            .handshake::<S, Body>(target_stream)
+            .await?;
+        tokio::spawn(connection);
+        Ok(ThirdWheel::new(
+            request_sender,
+            key.clone(),
+            version,
+            self.max_pending_requests,
+            Arc::downgrade(self),
+        ))
+    }
+}
+
+/// The protocols to offer a client so that it speaks the same HTTP version as
+/// the upstream
+pub(crate) fn alpn_protocols_for(version: Version) -> &'static [&'static str] {
+    if version == Version::HTTP_2 {
+        &[H2, HTTP_1_1]
+    } else {
+        &[HTTP_1_1]
+    }
+}
+
+pub(crate) fn negotiated_version(alpn: Option<Vec<u8>>) -> Version {
+    match alpn {
+        Some(protocol) if protocol == H2.as_bytes() => Version::HTTP_2,
+        _ => Version::HTTP_11,
+    }
+}
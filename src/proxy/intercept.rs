@@ -0,0 +1,161 @@
+use std::net::SocketAddr;
+
+/// Decides which CONNECT tunnels the proxy intercepts.
+///
+/// Tunnels that are not intercepted are spliced byte for byte to the upstream,
+/// so the client sees the upstream's real certificate and the mitm layer sees
+/// nothing.
+///
+/// Closures of the form `Fn(&str, &str, SocketAddr) -> bool`, taking the
+/// CONNECT host and port and the client's address, implement this trait.
+pub trait InterceptFilter: Send + Sync {
+    fn intercept(&self, host: &str, port: &str, client_addr: SocketAddr) -> bool;
+}
+
+impl<F> InterceptFilter for F
+where
+    F: Fn(&str, &str, SocketAddr) -> bool + Send + Sync,
+{
+    fn intercept(&self, host: &str, port: &str, client_addr: SocketAddr) -> bool {
+        self(host, port, client_addr)
+    }
+}
+
+/// Intercept every tunnel. This is the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct InterceptAll;
+
+impl InterceptFilter for InterceptAll {
+    fn intercept(&self, _: &str, _: &str, _: SocketAddr) -> bool {
+        true
+    }
+}
+
+/// A list of host name globs, where `*` matches any run of characters.
+///
+/// `*.example.com` matches subdomains at any depth but not `example.com`
+/// itself. Matching ignores case, a trailing dot and any port after the host.
+#[derive(Clone, Debug)]
+pub struct HostPatterns {
+    patterns: Vec<String>,
+    intercept_matching: bool,
+}
+
+impl HostPatterns {
+    /// Intercept only the hosts matching one of `patterns`
+    pub fn only<S: AsRef<str>>(patterns: &[S]) -> Self {
+        Self::new(patterns, true)
+    }
+
+    /// Intercept every host except those matching one of `patterns`
+    pub fn except<S: AsRef<str>>(patterns: &[S]) -> Self {
+        Self::new(patterns, false)
+    }
+
+    fn new<S: AsRef<str>>(patterns: &[S], intercept_matching: bool) -> Self {
+        Self {
+            patterns: patterns
+                .iter()
+                .map(|pattern| normalize(pattern.as_ref()))
+                .collect(),
+            intercept_matching,
+        }
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        let host = normalize(host);
+        self.patterns
+            .iter()
+            .any(|pattern| glob_matches(pattern.as_bytes(), host.as_bytes()))
+    }
+}
+
+impl InterceptFilter for HostPatterns {
+    fn intercept(&self, host: &str, _: &str, _: SocketAddr) -> bool {
+        self.matches(host) == self.intercept_matching
+    }
+}
+
+/// `host` in lower case without a trailing dot, port or IPv6 brackets
+fn normalize(host: &str) -> String {
+    let host = host.strip_prefix('[').map_or_else(
+        // More than one colon is a bare IPv6 address rather than a port
+        || match host.rsplit_once(':') {
+            Some((name, _)) if !name.contains(':') => name,
+            _ => host,
+        },
+        |bracketed| bracketed.split(']').next().unwrap_or(bracketed),
+    );
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn glob_matches(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Where to resume if the text so far doesn't fit: just after the last `*`
+    // seen, with that `*` swallowing one more character
+    let mut backtrack = None;
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            backtrack = Some((p + 1, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == text[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            p = star_p;
+            t = star_t + 1;
+            backtrack = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, host: &str) -> bool {
+        HostPatterns::only(&[pattern]).matches(host)
+    }
+
+    #[test]
+    fn wildcard_matches_subdomains_but_not_the_apex() {
+        assert!(matches("*.example.com", "www.example.com"));
+        assert!(matches("*.example.com", "a.b.example.com"));
+        assert!(!matches("*.example.com", "example.com"));
+        assert!(!matches("*.example.com", "www.example.com.evil"));
+        assert!(!matches("*.example.com", "wwwexample.com"));
+    }
+
+    #[test]
+    fn matching_ignores_case() {
+        assert!(matches("*.Example.COM", "www.example.com"));
+        assert!(matches("*.example.com", "WWW.EXAMPLE.Com"));
+    }
+
+    #[test]
+    fn matching_ignores_trailing_dots() {
+        assert!(matches("*.example.com", "www.example.com."));
+        assert!(matches("*.example.com.", "www.example.com"));
+        assert!(!matches("*.example.com", "example.com."));
+    }
+
+    #[test]
+    fn matching_ignores_the_port() {
+        assert!(matches("*.example.com", "www.example.com:443"));
+        assert!(!matches("*.example.com", "example.com:443"));
+        assert!(matches("::1", "[::1]:443"));
+        assert!(matches("::1", "::1"));
+    }
+
+    #[test]
+    fn except_intercepts_what_does_not_match() {
+        let client: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let patterns = HostPatterns::except(&["*.example.com"]);
+        assert!(!patterns.intercept("www.example.com", "443", client));
+        assert!(patterns.intercept("example.com", "443", client));
+    }
+}
@@ -1,72 +1,329 @@
 use std::pin::Pin;
 
+use super::{
+    connector::Connector,
+    pool::{PoolKey, UpstreamConnection},
+    upstream::Upstream,
+    websocket::{interceptor_for, splice_upgrade, take_upgrade},
+    Checkin,
+};
 use crate::error::Error;
-use futures::Future;
-use http::{header::HeaderName, Request, Response};
-use hyper::{client::conn::SendRequest, service::Service, Body};
+use futures::{Future, StreamExt};
+use http::{
+    header::{HeaderName, HeaderValue, HOST},
+    uri::Scheme,
+    Request, Response, Uri, Version,
+};
+use hyper::{
+    client::conn::{ResponseFuture, SendRequest},
+    service::Service,
+    Body,
+};
 use log::error;
+use std::sync::Weak;
+use std::task::Poll;
 use tokio::sync::{mpsc, oneshot};
+use tokio_util::sync::PollSender;
 use tower::Layer;
 
 type ResponseSender = oneshot::Sender<Result<Response<Body>, Error>>;
+type QueuedRequest = (ResponseSender, Request<Body>);
 
-pub(crate) struct RequestSendingSynchronizer {
+/// Feeds requests queued by `ThirdWheel`s to the upstream connection as fast
+/// as the connection will take them. HTTP/1.1 connections take one request at
+/// a time, HTTP/2 connections take as many as the server allows streams.
+pub(crate) struct RequestDispatcher {
     request_sender: SendRequest<Body>,
-    receiver: mpsc::UnboundedReceiver<(ResponseSender, Request<Body>)>,
+    receiver: mpsc::Receiver<QueuedRequest>,
+    version: Version,
 }
 
-impl RequestSendingSynchronizer {
+impl RequestDispatcher {
+    /// `version` is the HTTP version spoken on `request_sender`'s connection,
+    /// which need not match the version the client used
     pub(crate) const fn new(
         request_sender: SendRequest<Body>,
-        receiver: mpsc::UnboundedReceiver<(ResponseSender, Request<Body>)>,
+        receiver: mpsc::Receiver<QueuedRequest>,
+        version: Version,
     ) -> Self {
         Self {
             request_sender,
             receiver,
+            version,
         }
     }
 
     pub(crate) async fn run(&mut self) {
-        while let Some((sender, mut request)) = self.receiver.recv().await {
-            let relativized_uri = request
-                .uri()
-                .path_and_query()
-                .ok_or_else(|| Error::RequestError("URI did not contain a path".to_string()))
-                .and_then(|path| {
-                    path.as_str()
-                        .parse()
-                        .map_err(|_| Error::RequestError("Given URI was invalid".to_string()))
-                });
-            let response_fut = relativized_uri.map(|path| {
-                *request.uri_mut() = path;
-                // TODO: don't have this unnecessary overhead every time
-                let proxy_connection: HeaderName = HeaderName::from_lowercase(b"proxy-connection")
-                    .expect("Infallible: hardcoded header name");
-                request.headers_mut().remove(&proxy_connection);
-                self.request_sender.send_request(request)
-            });
-            let response_to_send = match response_fut {
-                Ok(response) => response.await.map_err(|e| e.into()),
-                Err(e) => Err(e),
+        // Only take a request off the queue once the connection can send it, so
+        // a slow upstream backs requests up into `ThirdWheel::poll_ready`
+        while futures::future::poll_fn(|cx| self.request_sender.poll_ready(cx))
+            .await
+            .is_ok()
+        {
+            let Some((sender, request)) = self.receiver.recv().await else {
+                return;
             };
-            if let Err(e) = sender.send(response_to_send) {
-                error!("Requester not available to receive request {:?}", e);
+            let response_fut = self.send_request(request);
+            tokio::spawn(async move {
+                let response_to_send = match response_fut {
+                    Ok(response) => response.await.map_err(Into::into),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = sender.send(response_to_send) {
+                    error!("Requester not available to receive request {e:?}");
+                }
+            });
+        }
+        log::debug!("Upstream connection closed");
+    }
+
+    fn send_request(&mut self, mut request: Request<Body>) -> Result<ResponseFuture, Error> {
+        let target_uri = if self.version == Version::HTTP_2 {
+            absolutized_uri(&request)
+        } else {
+            relativized_uri(&request)
+        }?;
+        // HTTP/2 clients put the authority in the URI rather than a Host
+        // header, which HTTP/1.1 servers require
+        if self.version != Version::HTTP_2 && !request.headers().contains_key(HOST) {
+            if let Some(authority) = request.uri().authority() {
+                if let Ok(host) = HeaderValue::from_str(authority.as_str()) {
+                    request.headers_mut().insert(HOST, host);
+                }
             }
         }
+        *request.uri_mut() = target_uri;
+        *request.version_mut() = self.version;
+        // TODO: don't have this unnecessary overhead every time
+        let proxy_connection: HeaderName = HeaderName::from_lowercase(b"proxy-connection")
+            .expect("Infallible: hardcoded header name");
+        request.headers_mut().remove(&proxy_connection);
+        Ok(self.request_sender.send_request(request))
     }
 }
 
+/// The origin-form URI HTTP/1.1 servers expect, e.g. `/path?query`
+fn relativized_uri(request: &Request<Body>) -> Result<Uri, Error> {
+    request
+        .uri()
+        .path_and_query()
+        .ok_or_else(|| Error::RequestError("URI did not contain a path".to_string()))
+        .and_then(|path| {
+            path.as_str()
+                .parse()
+                .map_err(|_| Error::RequestError("Given URI was invalid".to_string()))
+        })
+}
+
+/// The absolute URI HTTP/2 needs for its `:scheme` and `:authority` fields,
+/// taking the authority from the Host header if the client sent HTTP/1.1
+fn absolutized_uri(request: &Request<Body>) -> Result<Uri, Error> {
+    let authority = match request.uri().authority() {
+        Some(authority) => authority.as_str().to_string(),
+        None => request
+            .headers()
+            .get(HOST)
+            .and_then(|host| host.to_str().ok())
+            .map(std::string::ToString::to_string)
+            .ok_or_else(|| Error::RequestError("No authority found for request".to_string()))?,
+    };
+    let path = request
+        .uri()
+        .path_and_query()
+        .map_or("/", http::uri::PathAndQuery::as_str);
+    Uri::builder()
+        .scheme("https")
+        .authority(authority.as_str())
+        .path_and_query(path)
+        .build()
+        .map_err(|_| Error::RequestError("Given URI was invalid".to_string()))
+}
+
 /// A service that will proxy traffic to a target server and return unmodified responses
-#[derive(Clone)]
+///
+/// Every clone of a `ThirdWheel` shares one upstream connection and a queue of
+/// the requests waiting on it. `poll_ready` is pending while that queue is
+/// full and fails once the upstream connection has closed.
 pub struct ThirdWheel {
-    sender: mpsc::UnboundedSender<(ResponseSender, Request<Body>)>,
+    sender: PollSender<QueuedRequest>,
+    /// Whether `poll_ready` has reserved a place in the queue for `call`
+    reserved: bool,
+    /// For requests sent elsewhere with `call_to`. Weak as the connector's
+    /// pool holds `ThirdWheel`s itself.
+    connector: Weak<Connector>,
+    /// Where the connection goes and the HTTP version it speaks, for handling
+    /// upgrades. `None` for lazy `ThirdWheel`s, which leave upgrades to the
+    /// connection they hand requests to.
+    upstream: Option<(PoolKey, Version)>,
 }
 
 impl ThirdWheel {
-    pub(crate) const fn new(
-        sender: mpsc::UnboundedSender<(ResponseSender, Request<Body>)>,
+    /// Start dispatching requests on `request_sender`, allowing at most
+    /// `max_pending_requests` to be queued waiting for it
+    pub(crate) fn new(
+        request_sender: SendRequest<Body>,
+        key: PoolKey,
+        version: Version,
+        max_pending_requests: usize,
+        connector: Weak<Connector>,
     ) -> Self {
-        Self { sender }
+        let (sender, receiver) = mpsc::channel(max_pending_requests.max(1));
+        tokio::spawn(async move {
+            RequestDispatcher::new(request_sender, receiver, version)
+                .run()
+                .await;
+        });
+        Self {
+            sender: PollSender::new(sender),
+            reserved: false,
+            connector,
+            upstream: Some((key, version)),
+        }
+    }
+
+    /// A `ThirdWheel` that only opens its upstream connection with `connect`
+    /// once the first request arrives, and then forwards every request to it.
+    /// The connection is handed to `release` after every clone is dropped.
+    pub(crate) fn lazy<C, R>(
+        connect: C,
+        release: R,
+        max_pending_requests: usize,
+        connector: Weak<Connector>,
+    ) -> Self
+    where
+        C: Future<Output = Result<UpstreamConnection, Error>> + Send + 'static,
+        R: FnOnce(UpstreamConnection) + Send + 'static,
+    {
+        let (sender, mut receiver) = mpsc::channel::<QueuedRequest>(max_pending_requests.max(1));
+        tokio::spawn(async move {
+            let Some(first) = receiver.recv().await else {
+                return;
+            };
+            let connection = match connect.await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    // Fail everything already queued and refuse anything more
+                    let message = e.to_string();
+                    let (sender, _) = first;
+                    if let Err(e) = sender.send(Err(e)) {
+                        error!("Requester not available to receive request {e:?}");
+                    }
+                    receiver.close();
+                    while let Some((sender, _)) = receiver.recv().await {
+                        if let Err(e) = sender.send(Err(Error::ServerError(message.clone()))) {
+                            error!("Requester not available to receive request {e:?}");
+                        }
+                    }
+                    return;
+                }
+            };
+            let mut upstream = connection.third_wheel.clone();
+            let mut next = Some(first);
+            while let Some((sender, request)) = next {
+                // Only take the next request off the queue once the connection
+                // has room for this one, so a slow upstream backs requests up
+                // into this `ThirdWheel`'s `poll_ready`
+                let response_fut =
+                    match futures::future::poll_fn(|cx| upstream.poll_ready(cx)).await {
+                        Ok(()) => upstream.call(request),
+                        Err(e) => Box::pin(futures::future::ready(Err(e))),
+                    };
+                tokio::spawn(async move {
+                    let response_to_send = response_fut.await;
+                    if let Err(e) = sender.send(response_to_send) {
+                        error!("Requester not available to receive request {e:?}");
+                    }
+                });
+                next = receiver.recv().await;
+            }
+            release(connection);
+        });
+        Self {
+            sender: PollSender::new(sender),
+            reserved: false,
+            connector,
+            upstream: None,
+        }
+    }
+
+    /// Whether the upstream connection has gone away
+    pub(crate) fn is_closed(&self) -> bool {
+        self.sender.get_ref().is_none_or(mpsc::Sender::is_closed)
+    }
+
+    /// Send `request` to `upstream` instead of the tunnel's own upstream, for
+    /// routing individual requests elsewhere. The connection is taken from
+    /// the proxy's pool and made with its TLS settings and any upstream
+    /// proxy, and is plain HTTP if the request's URI has the `http` scheme.
+    /// The request's Host header is replaced to match `upstream`.
+    pub fn call_to(
+        &self,
+        upstream: Upstream,
+        mut request: Request<Body>,
+    ) -> <Self as Service<Request<Body>>>::Future {
+        let connector = self.connector.upgrade();
+        Box::pin(async move {
+            let connector = connector
+                .ok_or_else(|| Error::ServerError("The proxy has shut down".to_string()))?;
+            let plain = request.uri().scheme() == Some(&Scheme::HTTP);
+            let connection = if plain {
+                connector.checkout(&upstream).await?
+            } else {
+                connector.checkout_with_tls(&upstream).await?
+            };
+            *request.uri_mut() = rerouted_uri(&request, &upstream, plain)?;
+            request.headers_mut().remove(HOST);
+            let mut third_wheel = connection.third_wheel.clone();
+            let checkin = Checkin {
+                connector,
+                connection: Some(connection),
+            };
+            let response = third_wheel.call(request).await?;
+
+            // The body may still be streaming on the connection, so it only goes
+            // back to the pool once the body is done with
+            let (parts, body) = response.into_parts();
+            let body = Body::wrap_stream(body.inspect(move |_| {
+                let _ = &checkin;
+            }));
+            Ok(Response::from_parts(parts, body))
+        })
+    }
+}
+
+/// The request's URI with the authority of `upstream` in place of its own
+fn rerouted_uri(request: &Request<Body>, upstream: &Upstream, plain: bool) -> Result<Uri, Error> {
+    let (scheme, default_port) = if plain {
+        (Scheme::HTTP, "80")
+    } else {
+        (Scheme::HTTPS, "443")
+    };
+    let authority = if upstream.port == default_port {
+        upstream.sni.clone()
+    } else {
+        format!("{}:{}", upstream.sni, upstream.port)
+    };
+    let path = request
+        .uri()
+        .path_and_query()
+        .map_or("/", http::uri::PathAndQuery::as_str);
+    Uri::builder()
+        .scheme(scheme)
+        .authority(authority.as_str())
+        .path_and_query(path)
+        .build()
+        .map_err(|_| Error::RequestError("Given URI was invalid".to_string()))
+}
+
+impl Clone for ThirdWheel {
+    /// Clones share the queue but not any place reserved in it by `poll_ready`
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            reserved: false,
+            connector: self.connector.clone(),
+            upstream: self.upstream.clone(),
+        }
     }
 }
 
@@ -80,25 +337,96 @@ impl Service<Request<Body>> for ThirdWheel {
 
     fn poll_ready(
         &mut self,
-        _: &mut std::task::Context<'_>,
+        cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), Self::Error>> {
-        std::task::Poll::Ready(Ok(()))
+        if self.is_closed() {
+            return Poll::Ready(Err(Error::ServerError(
+                "Upstream connection is closed".to_string(),
+            )));
+        }
+        if !self.reserved {
+            match self.sender.poll_reserve(cx) {
+                Poll::Ready(Ok(())) => self.reserved = true,
+                Poll::Ready(Err(_)) => {
+                    return Poll::Ready(Err(Error::ServerError(
+                        "Upstream connection is closed".to_string(),
+                    )))
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
     }
 
     /// `ThirdWheel` performs very little modification of the request before
     /// transmitting it, but it does remove the proxy-connection header to
     /// ensure this is not passed to the target
-    fn call(&mut self, request: Request<Body>) -> Self::Future {
+    ///
+    /// If the upstream agrees to an upgrade the request asks for, such as to
+    /// a WebSocket, the client's and upstream's connections are joined once
+    /// the response has been sent to the client
+    fn call(&mut self, mut request: Request<Body>) -> Self::Future {
+        let connector = self.connector.clone();
+        let upgrade = self.upstream.clone().and_then(|(key, version)| {
+            let client = take_upgrade(&mut request)?;
+            let interceptor = connector
+                .upgrade()
+                .and_then(|connector| connector.websocket_interceptor());
+            Some((
+                client,
+                interceptor_for(&mut request, interceptor),
+                key,
+                version,
+            ))
+        });
+        if let Some((client, interceptor, key, Version::HTTP_2)) = upgrade {
+            // HTTP/2 can't carry an upgrade, so the request gets a HTTP/1.1
+            // connection of its own
+            if std::mem::take(&mut self.reserved) {
+                self.sender.abort_send();
+            }
+            return Box::pin(async move {
+                let connector = connector
+                    .upgrade()
+                    .ok_or_else(|| Error::ServerError("The proxy has shut down".to_string()))?;
+                let response = connector
+                    .connect_for_upgrade(&key)
+                    .await?
+                    .call(request)
+                    .await?;
+                Ok(splice_upgrade(response, client, interceptor))
+            });
+        }
         let (response_sender, response_receiver) = oneshot::channel();
-        let sender = self.sender.clone();
+        let unreserved = if std::mem::take(&mut self.reserved) {
+            if self.sender.send_item((response_sender, request)).is_err() {
+                return Box::pin(futures::future::ready(Err(Error::ServerError(
+                    "Upstream connection is closed".to_string(),
+                ))));
+            }
+            None
+        } else {
+            Some((self.sender.get_ref().cloned(), response_sender, request))
+        };
         let fut = async move {
-            //TODO: clarify what errors are possible here
-            sender.send((response_sender, request)).map_err(|_| {
-                Error::ServerError("Failed to connect to server correctly".to_string())
-            })?;
-            response_receiver
-                .await
-                .map_err(|_| Error::ServerError("Failed to get response from server".to_string()))?
+            // Callers that skipped `poll_ready` wait for room in the queue here
+            if let Some((sender, response_sender, request)) = unreserved {
+                //TODO: clarify what errors are possible here
+                sender
+                    .ok_or_else(|| Error::ServerError("Upstream connection is closed".to_string()))?
+                    .send((response_sender, request))
+                    .await
+                    .map_err(|_| {
+                        Error::ServerError("Failed to connect to server correctly".to_string())
+                    })?;
+            }
+            let response = response_receiver.await.map_err(|_| {
+                Error::ServerError("Failed to get response from server".to_string())
+            })??;
+            Ok(match upgrade {
+                Some((client, interceptor, _, _)) => splice_upgrade(response, client, interceptor),
+                None => response,
+            })
         };
         Box::pin(fut)
     }
@@ -134,7 +462,11 @@ where
     }
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
-        (self.f)(req, self.inner.clone())
+        // Hand over the service that was just made ready and keep a fresh clone
+        // to be readied for the next request
+        let inner = self.inner.clone();
+        let inner = std::mem::replace(&mut self.inner, inner);
+        (self.f)(req, inner)
     }
 }
 
@@ -172,3 +504,58 @@ where
 {
     MitmLayer { f }
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::future::poll_fn;
+    use tokio::time::timeout;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn poll_ready_is_pending_once_max_pending_requests_are_queued() {
+        // An upstream that takes requests but never answers them
+        let (client, _server) = tokio::io::duplex(1 << 16);
+        let (request_sender, connection) = hyper::client::conn::handshake(client).await.unwrap();
+        tokio::spawn(connection);
+        let key = PoolKey {
+            upstream: Upstream::new("example.com", "443"),
+            tls: true,
+        };
+        let mut third_wheel =
+            ThirdWheel::new(request_sender, key, Version::HTTP_11, 2, Weak::new());
+
+        // The first request is sent upstream and two more wait behind it
+        for _ in 0..3 {
+            timeout(
+                Duration::from_secs(5),
+                poll_fn(|cx| third_wheel.poll_ready(cx)),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            let request = Request::get("/")
+                .header(HOST, "example.com")
+                .body(Body::empty())
+                .unwrap();
+            // Queued as soon as it is called, whether or not it's awaited
+            let _response = third_wheel.call(request);
+        }
+        assert!(timeout(
+            Duration::from_secs(1),
+            poll_fn(|cx| third_wheel.poll_ready(cx))
+        )
+        .await
+        .is_err());
+        // Clones share the queue
+        let mut clone = third_wheel.clone();
+        assert!(
+            timeout(Duration::from_secs(1), poll_fn(|cx| clone.poll_ready(cx)))
+                .await
+                .is_err()
+        );
+    }
+}
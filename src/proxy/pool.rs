@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use http::Version;
+use openssl::x509::X509;
+use tokio::time::Instant;
+
+use super::{mitm::ThirdWheel, tls_policy::UpstreamVerification, upstream::Upstream};
+
+/// Identifies upstream connections that are interchangeable
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct PoolKey {
+    pub(crate) upstream: Upstream,
+    pub(crate) tls: bool,
+}
+
+/// An upstream connection with a running HTTP client, ready for requests
+#[derive(Clone)]
+pub(crate) struct UpstreamConnection {
+    pub(crate) key: PoolKey,
+    pub(crate) third_wheel: ThirdWheel,
+    /// The server's certificate, for TLS connections
+    pub(crate) certificate: Option<X509>,
+    /// How the server's certificate was trusted, for TLS connections
+    pub(crate) verification: Option<UpstreamVerification>,
+    pub(crate) version: Version,
+}
+
+struct IdleConnection {
+    connection: UpstreamConnection,
+    idle_since: Instant,
+}
+
+#[derive(Default)]
+struct IdleConnections {
+    by_key: HashMap<PoolKey, Vec<IdleConnection>>,
+    /// Whether a task is running `reap`
+    reaping: bool,
+}
+
+/// Upstream connections that finished serving a tunnel and can be handed to
+/// the next tunnel for the same upstream, saving a TCP and TLS handshake.
+pub(crate) struct ConnectionPool {
+    idle_timeout: Duration,
+    max_idle_per_host: usize,
+    idle: Arc<Mutex<IdleConnections>>,
+}
+
+impl ConnectionPool {
+    pub(crate) fn new(idle_timeout: Duration, max_idle_per_host: usize) -> Self {
+        Self {
+            idle_timeout,
+            max_idle_per_host,
+            idle: Arc::new(Mutex::new(IdleConnections::default())),
+        }
+    }
+
+    /// Take the most recently used live connection for `key`, if any
+    pub(crate) fn checkout(&self, key: &PoolKey) -> Option<UpstreamConnection> {
+        let mut idle = self.idle.lock().expect("connection pool lock poisoned");
+        evict_expired(&mut idle.by_key, self.idle_timeout);
+        let connections = idle.by_key.get_mut(key)?;
+        let connection = connections.pop().map(|idle| idle.connection);
+        if connections.is_empty() {
+            idle.by_key.remove(key);
+        }
+        connection
+    }
+
+    /// Return a connection to the pool once its tunnel has finished with it.
+    /// Closed and untrusted connections and those beyond the per-host limit
+    /// are dropped.
+    pub(crate) fn checkin(&self, connection: UpstreamConnection) {
+        if self.max_idle_per_host == 0
+            || connection.third_wheel.is_closed()
+            || connection.verification == Some(UpstreamVerification::Failed)
+        {
+            return;
+        }
+        let mut idle = self.idle.lock().expect("connection pool lock poisoned");
+        evict_expired(&mut idle.by_key, self.idle_timeout);
+        let connections = idle.by_key.entry(connection.key.clone()).or_default();
+        if connections.len() >= self.max_idle_per_host {
+            connections.remove(0);
+        }
+        connections.push(IdleConnection {
+            connection,
+            idle_since: Instant::now(),
+        });
+        if idle.reaping {
+            return;
+        }
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            idle.reaping = true;
+            drop(idle);
+            runtime.spawn(reap(Arc::downgrade(&self.idle), self.idle_timeout));
+        }
+    }
+}
+
+/// Close idle connections as they expire, whether or not the pool is used
+/// in the meantime. Stops once the pool is empty or has been dropped.
+async fn reap(idle: Weak<Mutex<IdleConnections>>, idle_timeout: Duration) {
+    loop {
+        let next_expiry = idle.upgrade().and_then(|idle| {
+            let mut idle = idle.lock().expect("connection pool lock poisoned");
+            evict_expired(&mut idle.by_key, idle_timeout);
+            let next_expiry = idle
+                .by_key
+                .values()
+                .flatten()
+                .map(|connection| connection.idle_since + idle_timeout)
+                .min();
+            if next_expiry.is_none() {
+                idle.reaping = false;
+            }
+            next_expiry
+        });
+        match next_expiry {
+            Some(next_expiry) => tokio::time::sleep_until(next_expiry).await,
+            None => return,
+        }
+    }
+}
+
+/// Dropping a connection's last `ThirdWheel` closes it
+fn evict_expired(idle: &mut HashMap<PoolKey, Vec<IdleConnection>>, idle_timeout: Duration) {
+    let now = Instant::now();
+    for connections in idle.values_mut() {
+        connections.retain(|idle| {
+            now.duration_since(idle.idle_since) < idle_timeout
+                && !idle.connection.third_wheel.is_closed()
+        });
+    }
+    idle.retain(|_, connections| !connections.is_empty());
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::sync::Weak;
+
+    use tokio::io::DuplexStream;
+
+    use super::*;
+
+    /// A connection to `host`, and the server's end of it to be kept open
+    async fn connection_to(host: &str) -> (UpstreamConnection, DuplexStream) {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let (request_sender, connection) = hyper::client::conn::handshake(client).await.unwrap();
+        tokio::spawn(connection);
+        let key = PoolKey {
+            upstream: Upstream::new(host, "443"),
+            tls: true,
+        };
+        let connection = UpstreamConnection {
+            third_wheel: ThirdWheel::new(
+                request_sender,
+                key.clone(),
+                Version::HTTP_11,
+                1,
+                Weak::new(),
+            ),
+            key,
+            certificate: None,
+            verification: Some(UpstreamVerification::Verified),
+            version: Version::HTTP_11,
+        };
+        (connection, server)
+    }
+
+    /// Move the paused clock on, letting the reaper run if it's due
+    async fn advance(duration: Duration) {
+        tokio::time::advance(duration).await;
+        tokio::task::yield_now().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_connections_are_closed_without_the_pool_being_used() {
+        let pool = ConnectionPool::new(Duration::from_secs(2), 4);
+        let (a, _a_server) = connection_to("a.example").await;
+        pool.checkin(a);
+        advance(Duration::from_secs(1)).await;
+        let (b, _b_server) = connection_to("b.example").await;
+        pool.checkin(b);
+        assert_eq!(pool.idle.lock().unwrap().by_key.len(), 2);
+
+        advance(Duration::from_millis(1500)).await;
+        assert_eq!(pool.idle.lock().unwrap().by_key.len(), 1);
+        advance(Duration::from_secs(1)).await;
+        assert!(pool.idle.lock().unwrap().by_key.is_empty());
+        assert!(!pool.idle.lock().unwrap().reaping);
+    }
+}
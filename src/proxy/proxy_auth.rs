@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+
+use http::{
+    header::{PROXY_AUTHENTICATE, PROXY_AUTHORIZATION},
+    HeaderValue, Request, Response, StatusCode,
+};
+use hyper::Body;
+
+/// What clients that fail to authenticate are told to try
+const CHALLENGE: &str = "Basic realm=\"third-wheel\"";
+
+/// Checks the username and password clients give in the `Proxy-Authorization`
+/// header of their CONNECT and plain HTTP requests, with the Basic scheme.
+///
+/// `authenticate` returns the principal the client is known by, which the
+/// proxy puts in `ConnectionInfo::principal`, or `None` to refuse the client
+/// with a 407.
+///
+/// A map of usernames to passwords implements this trait, with the username
+/// as the principal, as do closures of the form
+/// `Fn(&str, &str) -> Option<String>`.
+pub trait ProxyAuthenticator: Send + Sync {
+    fn authenticate(&self, username: &str, password: &str) -> Option<String>;
+}
+
+impl<F> ProxyAuthenticator for F
+where
+    F: Fn(&str, &str) -> Option<String> + Send + Sync,
+{
+    fn authenticate(&self, username: &str, password: &str) -> Option<String> {
+        self(username, password)
+    }
+}
+
+impl<S: BuildHasher + Send + Sync> ProxyAuthenticator for HashMap<String, String, S> {
+    fn authenticate(&self, username: &str, password: &str) -> Option<String> {
+        match self.get(username) {
+            // In constant time, so the time taken doesn't give the password away
+            Some(expected)
+                if expected.len() == password.len()
+                    && openssl::memcmp::eq(expected.as_bytes(), password.as_bytes()) =>
+            {
+                Some(username.to_string())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The principal the credentials in `request` belong to, if `authenticator`
+/// accepts them. The credentials are taken out of `request` either way, so
+/// they are never forwarded upstream.
+pub(crate) fn authenticate(
+    request: &mut Request<Body>,
+    authenticator: &dyn ProxyAuthenticator,
+) -> Option<String> {
+    let header = request.headers_mut().remove(PROXY_AUTHORIZATION)?;
+    let (scheme, credentials) = header.to_str().ok()?.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let credentials = openssl::base64::decode_block(credentials.trim()).ok()?;
+    let credentials = String::from_utf8(credentials).ok()?;
+    let (username, password) = credentials.split_once(':')?;
+    authenticator.authenticate(username, password)
+}
+
+/// The response asking a client that failed to authenticate to try again
+pub(crate) fn challenge() -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::PROXY_AUTHENTICATION_REQUIRED;
+    response
+        .headers_mut()
+        .insert(PROXY_AUTHENTICATE, HeaderValue::from_static(CHALLENGE));
+    response
+}
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use http::header::{ACCEPT, CONTENT_TYPE};
+use http::{Request, Uri};
+use hyper::Body;
+use openssl::x509::X509;
+
+use self::https::HttpsClient;
+use crate::error::Error;
+
+mod dns_message;
+mod https;
+#[cfg(feature = "odoh")]
+mod odoh;
+
+#[cfg(feature = "odoh")]
+pub use self::odoh::ObliviousDohResolver;
+
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// Turns upstream host names into the addresses the proxy connects to. Set
+/// with `MitmProxyBuilder::resolver` to control how, and through whom, the
+/// proxy's DNS lookups are made.
+pub trait Resolver: Send + Sync {
+    /// The addresses of `host`, to be tried in order. An empty list means the
+    /// host does not exist.
+    fn resolve<'a>(&'a self, host: &'a str) -> BoxFuture<'a, Result<Vec<IpAddr>, Error>>;
+}
+
+/// Resolves host names with the operating system's resolver, as the proxy
+/// does by default
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve<'a>(&'a self, host: &'a str) -> BoxFuture<'a, Result<Vec<IpAddr>, Error>> {
+        Box::pin(async move {
+            let addresses = tokio::net::lookup_host((host, 0)).await?;
+            Ok(addresses.map(|address| address.ip()).collect())
+        })
+    }
+}
+
+/// Resolves host names from a fixed map, handing any others to a fallback
+/// resolver. A host may be mapped to an address or to another host name,
+/// which is then resolved by the fallback.
+pub struct StaticResolver {
+    mappings: HashMap<String, String>,
+    fallback: Arc<dyn Resolver>,
+}
+
+impl StaticResolver {
+    /// Use `mappings`, falling back to the system resolver
+    #[must_use]
+    pub fn new(mappings: HashMap<String, String>) -> Self {
+        Self::with_fallback(mappings, SystemResolver)
+    }
+
+    /// Use `mappings`, falling back to `fallback`
+    pub fn with_fallback<R: Resolver + 'static>(
+        mappings: HashMap<String, String>,
+        fallback: R,
+    ) -> Self {
+        Self {
+            mappings,
+            fallback: Arc::new(fallback),
+        }
+    }
+}
+
+impl Resolver for StaticResolver {
+    fn resolve<'a>(&'a self, host: &'a str) -> BoxFuture<'a, Result<Vec<IpAddr>, Error>> {
+        let target = self.mappings.get(host).map_or(host, String::as_str);
+        match target.parse() {
+            Ok(address) => Box::pin(futures::future::ready(Ok(vec![address]))),
+            Err(_) => self.fallback.resolve(target),
+        }
+    }
+}
+
+/// Resolves host names by DNS over HTTPS (RFC 8484), so that the lookups are
+/// hidden from the network between the proxy and the DNS server
+#[derive(Clone)]
+pub struct DohResolver {
+    url: Uri,
+    client: HttpsClient,
+}
+
+impl DohResolver {
+    /// Use the DNS server at `url`, such as
+    /// `https://cloudflare-dns.com/dns-query`. The server's own name is
+    /// looked up with the system resolver unless it is given with
+    /// `bootstrap`.
+    pub fn new(url: &str) -> Result<Self, Error> {
+        let url: Uri = url.parse()?;
+        if url.scheme() != Some(&http::uri::Scheme::HTTPS) {
+            return Err(Error::DnsError(format!("{url} is not an https URL")));
+        }
+        Ok(Self {
+            url,
+            client: HttpsClient::default(),
+        })
+    }
+
+    /// Connect to the DNS server at `address` instead of looking up `host`
+    #[must_use]
+    pub fn bootstrap(mut self, host: &str, address: IpAddr) -> Self {
+        self.client.bootstrap.insert(host.to_string(), address);
+        self
+    }
+
+    /// Trust `certificate` as a root when checking the DNS server's
+    /// certificate, on top of the system's roots
+    #[must_use]
+    pub fn add_root_certificate(mut self, certificate: X509) -> Self {
+        self.client.root_certificates.push(certificate);
+        self
+    }
+
+    async fn query(&self, host: &str, record_type: u16) -> Result<Vec<IpAddr>, Error> {
+        let request = Request::post(self.url.clone())
+            .header(CONTENT_TYPE, DNS_MESSAGE)
+            .header(ACCEPT, DNS_MESSAGE)
+            .body(Body::from(dns_message::query(host, record_type)?))
+            .map_err(|e| Error::DnsError(e.to_string()))?;
+        let response = self.client.send(request).await?;
+        dns_message::addresses(&response)
+    }
+}
+
+impl Resolver for DohResolver {
+    fn resolve<'a>(&'a self, host: &'a str) -> BoxFuture<'a, Result<Vec<IpAddr>, Error>> {
+        Box::pin(async move {
+            let (v4, v6) = futures::join!(
+                self.query(host, dns_message::A),
+                self.query(host, dns_message::AAAA)
+            );
+            combine(v4, v6)
+        })
+    }
+}
+
+/// The IPv4 and IPv6 addresses of a host, failing only if both lookups did
+fn combine(
+    v4: Result<Vec<IpAddr>, Error>,
+    v6: Result<Vec<IpAddr>, Error>,
+) -> Result<Vec<IpAddr>, Error> {
+    match (v4, v6) {
+        (Ok(mut v4), Ok(v6)) => {
+            v4.extend(v6);
+            Ok(v4)
+        }
+        (Ok(addresses), Err(_)) | (Err(_), Ok(addresses)) => Ok(addresses),
+        (Err(e), Err(_)) => Err(e),
+    }
+}
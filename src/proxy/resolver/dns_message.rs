@@ -0,0 +1,205 @@
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::error::Error;
+use crate::proxy::reader::Reader;
+
+pub(crate) const A: u16 = 1;
+pub(crate) const AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const RECURSION_DESIRED: u16 = 0x0100;
+const NAME_ERROR: u16 = 3;
+
+/// A DNS query for the `record_type` records of `host`, in wire format. The
+/// ID is zero, as RFC 8484 recommends for DNS over HTTPS.
+pub(crate) fn query(host: &str, record_type: u16) -> Result<Vec<u8>, Error> {
+    let mut message = Vec::with_capacity(12 + host.len() + 6);
+    for field in &[0, RECURSION_DESIRED, 1, 0, 0, 0] {
+        message.extend_from_slice(&u16::to_be_bytes(*field));
+    }
+    for label in host.trim_end_matches('.').split('.') {
+        match u8::try_from(label.len()) {
+            Ok(length) if length > 0 && length < 64 => message.push(length),
+            _ => return Err(Error::DnsError(format!("{host} is not a valid host name"))),
+        }
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    message.extend_from_slice(&record_type.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(message)
+}
+
+/// The A and AAAA records in a DNS response. A host that doesn't exist has
+/// none.
+pub(crate) fn addresses(response: &[u8]) -> Result<Vec<IpAddr>, Error> {
+    parse_addresses(&mut Reader(response))
+        .unwrap_or_else(|| Err(Error::DnsError("Malformed DNS response".to_string())))
+}
+
+fn parse_addresses(response: &mut Reader<'_>) -> Option<Result<Vec<IpAddr>, Error>> {
+    response.take(2)?; // ID
+    let flags = response.u16()?;
+    let question_count = response.u16()?;
+    let answer_count = response.u16()?;
+    response.take(4)?; // authority and additional counts
+    match flags & 0xf {
+        0 => {}
+        NAME_ERROR => return Some(Ok(vec![])),
+        code => {
+            return Some(Err(Error::DnsError(format!(
+                "DNS server answered with response code {code}"
+            ))))
+        }
+    }
+
+    for _ in 0..question_count {
+        skip_name(response)?;
+        response.take(4)?; // type and class
+    }
+    let mut addresses = vec![];
+    for _ in 0..answer_count {
+        skip_name(response)?;
+        let record_type = response.u16()?;
+        response.take(6)?; // class and TTL
+        let data_length = response.u16()?;
+        let data = response.take(usize::from(data_length))?;
+        match record_type {
+            A if data.len() == 4 => {
+                addresses.push(IpAddr::V4(Ipv4Addr::new(
+                    data[0], data[1], data[2], data[3],
+                )));
+            }
+            AAAA if data.len() == 16 => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(data);
+                addresses.push(IpAddr::V6(Ipv6Addr::from(octets)));
+            }
+            // Such as the CNAMEs leading to the addresses
+            _ => {}
+        }
+    }
+    Some(Ok(addresses))
+}
+
+/// Read past a possibly compressed domain name
+fn skip_name(message: &mut Reader<'_>) -> Option<()> {
+    loop {
+        let length = message.u8()?;
+        if length == 0 {
+            return Some(());
+        }
+        // A pointer to the rest of the name elsewhere in the message
+        if length & 0xc0 == 0xc0 {
+            message.take(1)?;
+            return Some(());
+        }
+        message.take(usize::from(length))?;
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    const CNAME: u16 = 5;
+    const SERVER_FAILURE: u16 = 2;
+    /// A compression pointer to the name in the question
+    const QUESTION_NAME: &[u8] = &[0xc0, 12];
+
+    /// The answer to a query for example.com's A records
+    fn response(response_code: u16, answers: &[Vec<u8>]) -> Vec<u8> {
+        let mut message = query("example.com", A).unwrap();
+        message[2..4].copy_from_slice(&(0x8180 | response_code).to_be_bytes());
+        message[6..8].copy_from_slice(&u16::try_from(answers.len()).unwrap().to_be_bytes());
+        for answer in answers {
+            message.extend_from_slice(answer);
+        }
+        message
+    }
+
+    /// A resource record for `name`, which is already in wire format
+    fn record(name: &[u8], record_type: u16, data: &[u8]) -> Vec<u8> {
+        let mut record = name.to_vec();
+        record.extend_from_slice(&record_type.to_be_bytes());
+        record.extend_from_slice(&CLASS_IN.to_be_bytes());
+        record.extend_from_slice(&300_u32.to_be_bytes());
+        record.extend_from_slice(&u16::try_from(data.len()).unwrap().to_be_bytes());
+        record.extend_from_slice(data);
+        record
+    }
+
+    #[test]
+    fn query_is_encoded_in_wire_format() {
+        let expected = [
+            0, 0, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3,
+            b'c', b'o', b'm', 0, 0, 28, 0, 1,
+        ];
+        assert_eq!(query("example.com", AAAA).unwrap(), expected);
+        assert_eq!(query("example.com.", AAAA).unwrap(), expected);
+    }
+
+    #[test]
+    fn query_rejects_empty_labels() {
+        assert!(query("example..com", A).is_err());
+        assert!(query(".example.com", A).is_err());
+        assert!(query("", A).is_err());
+    }
+
+    #[test]
+    fn query_rejects_labels_longer_than_63_bytes() {
+        assert!(query(&format!("{}.com", "a".repeat(63)), A).is_ok());
+        assert!(query(&format!("{}.com", "a".repeat(64)), A).is_err());
+    }
+
+    #[test]
+    fn host_that_does_not_exist_has_no_addresses() {
+        assert_eq!(
+            addresses(&response(NAME_ERROR, &[])).unwrap(),
+            Vec::<IpAddr>::new()
+        );
+    }
+
+    #[test]
+    fn server_failure_is_an_error() {
+        assert!(matches!(
+            addresses(&response(SERVER_FAILURE, &[])),
+            Err(Error::DnsError(_))
+        ));
+    }
+
+    #[test]
+    fn answer_with_compressed_name_is_read() {
+        let answer = record(QUESTION_NAME, A, &[93, 184, 216, 34]);
+        assert_eq!(
+            addresses(&response(0, &[answer])).unwrap(),
+            vec![IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34))]
+        );
+    }
+
+    #[test]
+    fn addresses_after_a_cname_are_read() {
+        let canonical_name = b"\x03www\x07example\x03net\x00";
+        let answers = [
+            record(QUESTION_NAME, CNAME, canonical_name),
+            record(canonical_name, A, &[192, 0, 2, 1]),
+            record(canonical_name, AAAA, &Ipv6Addr::LOCALHOST.octets()),
+        ];
+        assert_eq!(
+            addresses(&response(0, &answers)).unwrap(),
+            vec![
+                IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+                IpAddr::V6(Ipv6Addr::LOCALHOST)
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated_response_is_an_error() {
+        let message = response(0, &[record(QUESTION_NAME, A, &[192, 0, 2, 1])]);
+        for length in &[0, 5, 12, 20, message.len() - 1] {
+            assert!(addresses(&message[..*length]).is_err(), "{} bytes", length);
+        }
+    }
+}
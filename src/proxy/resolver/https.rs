@@ -0,0 +1,64 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+use http::{header::HOST, Request, Uri};
+use hyper::body::Bytes;
+use hyper::Body;
+use openssl::x509::X509;
+use tokio::net::TcpStream;
+
+use crate::error::Error;
+use crate::tls::TlsConnector;
+
+/// A minimal HTTPS client for talking to DNS servers. Each request is sent on
+/// a fresh connection, as the proxy's own connector would resolve the DNS
+/// server's name through the very resolver being built.
+#[derive(Clone, Default)]
+pub(crate) struct HttpsClient {
+    pub(crate) root_certificates: Vec<X509>,
+    /// Addresses to use for host names rather than asking the system
+    pub(crate) bootstrap: HashMap<String, IpAddr>,
+}
+
+impl HttpsClient {
+    /// Send `request`, whose URI must be absolute, and return the body of a
+    /// successful response
+    pub(crate) async fn send(&self, mut request: Request<Body>) -> Result<Bytes, Error> {
+        let uri = request.uri().clone();
+        let host = uri
+            .host()
+            .ok_or_else(|| Error::DnsError(format!("{uri} has no host")))?;
+        let port = uri.port_u16().unwrap_or(443);
+        let target_stream = match self.bootstrap.get(host) {
+            Some(address) => TcpStream::connect(SocketAddr::new(*address, port)).await?,
+            None => TcpStream::connect((host, port)).await?,
+        };
+
+        let (target_stream, _, _) = TlsConnector::new(self.root_certificates.clone())
+            .connect(target_stream, host, None, true, &["http/1.1"])
+            .await?;
+        let (mut request_sender, connection) =
+            hyper::client::conn::handshake(target_stream).await?;
+        tokio::spawn(connection);
+
+        let authority = uri
+            .authority()
+            .map_or(host, http::uri::Authority::as_str)
+            .parse()
+            .map_err(|_| Error::DnsError(format!("{uri} has an invalid host")))?;
+        request.headers_mut().insert(HOST, authority);
+        *request.uri_mut() = uri
+            .path_and_query()
+            .map_or("/", http::uri::PathAndQuery::as_str)
+            .parse::<Uri>()?;
+        let response = request_sender.send_request(request).await?;
+        if !response.status().is_success() {
+            return Err(Error::DnsError(format!(
+                "{} answered with status {}",
+                uri,
+                response.status()
+            )));
+        }
+        Ok(hyper::body::to_bytes(response.into_body()).await?)
+    }
+}
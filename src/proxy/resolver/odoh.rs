@@ -0,0 +1,217 @@
+use std::net::IpAddr;
+
+use futures::future::BoxFuture;
+use http::header::{ACCEPT, CONTENT_TYPE};
+use http::{Request, Uri};
+use hyper::Body;
+use odoh_rs::{
+    compose, decrypt_response, encrypt_query, parse, ObliviousDoHConfigContents,
+    ObliviousDoHConfigs, ObliviousDoHMessage, ObliviousDoHMessagePlaintext,
+};
+use openssl::x509::X509;
+use tokio::sync::Mutex;
+
+use super::https::HttpsClient;
+use super::{combine, dns_message, Resolver};
+use crate::error::Error;
+
+const OBLIVIOUS_DNS_MESSAGE: &str = "application/oblivious-dns-message";
+const CONFIGS_PATH: &str = "/.well-known/odohconfigs";
+const QUERY_PATH: &str = "/dns-query";
+
+/// Resolves host names by Oblivious DNS over HTTPS (RFC 9230). Queries are
+/// encrypted for the target DNS server and sent by way of a proxy, so the
+/// proxy learns who is asking but not what, and the target what but not who.
+pub struct ObliviousDohResolver {
+    proxy_url: Uri,
+    target_host: String,
+    client: HttpsClient,
+    /// The target's public key, fetched on the first lookup and again
+    /// whenever a query made with it fails
+    config: Mutex<Option<ObliviousDoHConfigContents>>,
+}
+
+impl ObliviousDohResolver {
+    /// Send queries for `target_host`, such as `odoh.cloudflare-dns.com`,
+    /// through the oblivious proxy at `proxy_url`, such as
+    /// `https://odoh1.surfdomeinen.nl/proxy`
+    pub fn new(proxy_url: &str, target_host: &str) -> Result<Self, Error> {
+        let proxy_url: Uri = proxy_url.parse()?;
+        if proxy_url.scheme() != Some(&http::uri::Scheme::HTTPS) {
+            return Err(Error::DnsError(format!(
+                "{} is not an https URL",
+                proxy_url
+            )));
+        }
+        Ok(Self {
+            proxy_url,
+            target_host: target_host.to_string(),
+            client: HttpsClient::default(),
+            config: Mutex::new(None),
+        })
+    }
+
+    /// Connect to `host`, the proxy or the target, at `address` instead of
+    /// looking it up
+    #[must_use]
+    pub fn bootstrap(mut self, host: &str, address: IpAddr) -> Self {
+        self.client.bootstrap.insert(host.to_string(), address);
+        self
+    }
+
+    /// Trust `certificate` as a root when checking the proxy's and target's
+    /// certificates, on top of the system's roots
+    #[must_use]
+    pub fn add_root_certificate(mut self, certificate: X509) -> Self {
+        self.client.root_certificates.push(certificate);
+        self
+    }
+
+    /// The target's public key. It is fetched directly from the target, which
+    /// learns only that the proxy is about to make some queries.
+    async fn config(&self) -> Result<ObliviousDoHConfigContents, Error> {
+        let mut config = self.config.lock().await;
+        if let Some(config) = config.as_ref() {
+            return Ok(config.clone());
+        }
+        let request = Request::get(format!("https://{}{}", self.target_host, CONFIGS_PATH))
+            .body(Body::empty())
+            .map_err(|e| Error::DnsError(e.to_string()))?;
+        let mut response = self.client.send(request).await?;
+        let configs: ObliviousDoHConfigs = parse(&mut response).map_err(odoh_error)?;
+        let contents: ObliviousDoHConfigContents = configs
+            .supported()
+            .into_iter()
+            .next()
+            .ok_or_else(|| {
+                Error::DnsError(format!(
+                    "{} offers no supported ODoH configuration",
+                    self.target_host
+                ))
+            })?
+            .into();
+        *config = Some(contents.clone());
+        Ok(contents)
+    }
+
+    async fn query(&self, host: &str, record_type: u16) -> Result<Vec<IpAddr>, Error> {
+        let plaintext =
+            ObliviousDoHMessagePlaintext::new(&dns_message::query(host, record_type)?, 0);
+        let config = self.config().await?;
+        let response = match self.exchange(&plaintext, &config).await {
+            Ok(response) => response,
+            // The target may have rotated its key since it was fetched, so a
+            // query it rejected or whose answer can't be decrypted is tried
+            // once more with the target's current configuration
+            Err(e) => {
+                log::debug!(
+                    "ODoH query to {} failed, fetching its configuration again: {}",
+                    self.target_host,
+                    e
+                );
+                self.config.lock().await.take();
+                let config = self.config().await?;
+                self.exchange(&plaintext, &config).await?
+            }
+        };
+        dns_message::addresses(&response.into_msg())
+    }
+
+    /// Encrypt `plaintext` with `config`, send it through the proxy and
+    /// decrypt the target's answer
+    async fn exchange(
+        &self,
+        plaintext: &ObliviousDoHMessagePlaintext,
+        config: &ObliviousDoHConfigContents,
+    ) -> Result<ObliviousDoHMessagePlaintext, Error> {
+        let (query, secret) =
+            encrypt_query(plaintext, config, &mut rand::thread_rng()).map_err(odoh_error)?;
+        let body = compose(&query).map_err(odoh_error)?.freeze();
+
+        let mut url = self.proxy_url.clone().into_parts();
+        url.path_and_query = Some(
+            format!(
+                "{}?targethost={}&targetpath={}",
+                self.proxy_url.path(),
+                self.target_host,
+                QUERY_PATH
+            )
+            .parse()?,
+        );
+        let url = Uri::from_parts(url).map_err(|e| Error::DnsError(e.to_string()))?;
+        let request = Request::post(url)
+            .header(CONTENT_TYPE, OBLIVIOUS_DNS_MESSAGE)
+            .header(ACCEPT, OBLIVIOUS_DNS_MESSAGE)
+            .body(Body::from(body))
+            .map_err(|e| Error::DnsError(e.to_string()))?;
+        let mut response = self.client.send(request).await?;
+
+        let response: ObliviousDoHMessage = parse(&mut response).map_err(odoh_error)?;
+        decrypt_response(plaintext, &response, secret).map_err(odoh_error)
+    }
+}
+
+impl Resolver for ObliviousDohResolver {
+    fn resolve<'a>(&'a self, host: &'a str) -> BoxFuture<'a, Result<Vec<IpAddr>, Error>> {
+        Box::pin(async move {
+            // The A and AAAA queries are made in turn so the configuration is
+            // fetched only once
+            let v4 = self.query(host, dns_message::A).await;
+            let v6 = self.query(host, dns_message::AAAA).await;
+            combine(v4, v6)
+        })
+    }
+}
+
+fn odoh_error(error: odoh_rs::Error) -> Error {
+    Error::DnsError(format!("ODoH: {}", error))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use odoh_rs::{decrypt_query, encrypt_response, ObliviousDoHKeyPair, ResponseNonce};
+    use rand::Rng;
+
+    use super::*;
+
+    /// A query and its answer make the trip between the resolver and the
+    /// target, encrypted and in wire format, just as `exchange` sends them
+    #[test]
+    fn query_and_answer_survive_encryption() {
+        let mut rng = rand::thread_rng();
+        let key_pair = ObliviousDoHKeyPair::new(&mut rng);
+        let query_message = dns_message::query("example.com", dns_message::A).unwrap();
+        let plaintext = ObliviousDoHMessagePlaintext::new(&query_message, 0);
+
+        let (query, client_secret) =
+            encrypt_query(&plaintext, key_pair.public(), &mut rng).unwrap();
+        let received: ObliviousDoHMessage = parse(&mut compose(&query).unwrap().freeze()).unwrap();
+        let (received_plaintext, server_secret) = decrypt_query(&received, &key_pair).unwrap();
+        assert_eq!(received_plaintext.clone().into_msg(), query_message);
+
+        // The question with a single A record pointing back at its name
+        let mut answer = query_message;
+        answer[2..4].copy_from_slice(&0x8180_u16.to_be_bytes());
+        answer[6..8].copy_from_slice(&1_u16.to_be_bytes());
+        answer.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 1, 44, 0, 4, 192, 0, 2, 1]);
+        let nonce: ResponseNonce = rng.gen();
+        let response = encrypt_response(
+            &received_plaintext,
+            &ObliviousDoHMessagePlaintext::new(&answer, 0),
+            server_secret,
+            nonce,
+        )
+        .unwrap();
+
+        let answered: ObliviousDoHMessage =
+            parse(&mut compose(&response).unwrap().freeze()).unwrap();
+        let decrypted = decrypt_response(&plaintext, &answered, client_secret).unwrap();
+        assert_eq!(
+            dns_message::addresses(&decrypted.into_msg()).unwrap(),
+            vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))]
+        );
+    }
+}
@@ -0,0 +1,311 @@
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use http::Request;
+use hyper::{service::Service, Body};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tower::Layer;
+
+use super::connection_info::ConnectionInfo;
+use super::mitm::ThirdWheel;
+use super::proxy_auth::ProxyAuthenticator;
+use super::{open_tunnel, run_mitm_on_connection, splice, ProxyState, Tunnel};
+use crate::error::Error;
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const USERNAME_PASSWORD: u8 = 2;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const USERNAME_PASSWORD_VERSION: u8 = 1;
+const CONNECT: u8 = 1;
+const IPV4: u8 = 1;
+const DOMAIN_NAME: u8 = 3;
+const IPV6: u8 = 4;
+
+/// The reply codes of RFC 1928
+#[derive(Clone, Copy, Debug)]
+enum Reply {
+    Succeeded = 0,
+    GeneralFailure = 1,
+    HostUnreachable = 4,
+    ConnectionRefused = 5,
+    CommandNotSupported = 7,
+    AddressTypeNotSupported = 8,
+}
+
+/// Handle a SOCKS5 client: authenticate it, read the target of its CONNECT
+/// and then treat the connection as a CONNECT tunnel to that target
+pub(crate) async fn serve_socks5_connection<T, U>(
+    mut stream: TcpStream,
+    client_addr: SocketAddr,
+    state: ProxyState<T>,
+) -> Result<(), Error>
+where
+    T: Layer<ThirdWheel, Service = U> + Sync + std::marker::Send + 'static + Clone,
+    U: Service<Request<Body>, Response = <ThirdWheel as Service<Request<Body>>>::Response>
+        + Sync
+        + Send
+        + 'static
+        + Clone,
+    U::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    <U as Service<Request<Body>>>::Future: Send,
+{
+    // The proxy's authenticator covers SOCKS5 clients too, and takes the place
+    // of `socks5_credentials` if both are set
+    let authenticator: Option<&dyn ProxyAuthenticator> = match &state.proxy_authenticator {
+        Some(authenticator) => Some(authenticator.as_ref()),
+        None if state.socks5_credentials.is_empty() => None,
+        None => Some(state.socks5_credentials.as_ref()),
+    };
+    let principal = authenticate(&mut stream, authenticator).await?;
+    let (host, port) = match read_connect_request(&mut stream).await? {
+        Ok(target) => target,
+        Err(reply) => {
+            write_reply(&mut stream, reply).await?;
+            return Err(Error::RequestError(format!(
+                "Unsupported SOCKS5 request: {reply:?}"
+            )));
+        }
+    };
+
+    let upstream = state.upstream_resolver.resolve(&host, &port);
+    let mut info = ConnectionInfo::new(client_addr, format!("{host}:{port}"), upstream);
+    info.principal = principal;
+    let intercept = state.intercept_filter.intercept(&host, &port, client_addr);
+    // As for CONNECT, reach the upstream before telling the client the tunnel
+    // is open so that failures can be reported
+    let tunnel = match open_tunnel(
+        &state.spoofer,
+        &state.connector,
+        state.invalid_upstream_certificate,
+        intercept,
+        info,
+    )
+    .await
+    {
+        Ok(tunnel) => tunnel,
+        Err(e) => {
+            write_reply(&mut stream, reply_for(&e)).await?;
+            return Err(e);
+        }
+    };
+    if let Err(e) = write_reply(&mut stream, Reply::Succeeded).await {
+        tunnel.close(&state.connector);
+        return Err(e);
+    }
+    match tunnel {
+        Tunnel::Intercepted(tunnel) => {
+            run_mitm_on_connection(
+                stream,
+                *tunnel,
+                state.mitm,
+                state.connector,
+                &state.spoofer,
+                state.client_sni,
+                state.stream_interceptor,
+                state.server_speaks_first_timeout,
+            )
+            .await
+        }
+        Tunnel::Spliced(target_stream) => splice(stream, target_stream).await,
+    }
+}
+
+/// Ask the SOCKS5 proxy on the other end of `stream` for a connection to
+/// `host:port`, authenticating with `credentials` if given
+pub(crate) async fn connect_through(
+    stream: &mut TcpStream,
+    host: &str,
+    port: &str,
+    credentials: Option<&(String, String)>,
+) -> Result<(), Error> {
+    let method = if credentials.is_some() {
+        USERNAME_PASSWORD
+    } else {
+        NO_AUTHENTICATION
+    };
+    stream.write_all(&[VERSION, 1, method]).await?;
+    let mut chosen = [0; 2];
+    stream.read_exact(&mut chosen).await?;
+    if chosen != [VERSION, method] {
+        return Err(Error::ServerError(
+            "Upstream SOCKS5 proxy accepted no authentication method offered".to_string(),
+        ));
+    }
+    if let Some((username, password)) = credentials {
+        let mut message = vec![USERNAME_PASSWORD_VERSION];
+        write_string(&mut message, username)?;
+        write_string(&mut message, password)?;
+        stream.write_all(&message).await?;
+        let mut status = [0; 2];
+        stream.read_exact(&mut status).await?;
+        if status[1] != 0 {
+            return Err(Error::ServerError(
+                "Upstream SOCKS5 proxy refused the credentials".to_string(),
+            ));
+        }
+    }
+
+    let mut request = vec![VERSION, CONNECT, 0];
+    match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(IpAddr::V4(address)) => {
+            request.push(IPV4);
+            request.extend_from_slice(&address.octets());
+        }
+        Ok(IpAddr::V6(address)) => {
+            request.push(IPV6);
+            request.extend_from_slice(&address.octets());
+        }
+        Err(_) => {
+            request.push(DOMAIN_NAME);
+            write_string(&mut request, host)?;
+        }
+    }
+    let port: u16 = port
+        .parse()
+        .map_err(|_| Error::RequestError(format!("Invalid port {port}")))?;
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).await?;
+    if header[1] != Reply::Succeeded as u8 {
+        return Err(Error::ServerError(format!(
+            "Upstream SOCKS5 proxy refused connection to {}:{} with reply {}",
+            host, port, header[1]
+        )));
+    }
+    // The bound address is of no use, but must be read past
+    let address_length = match header[3] {
+        IPV4 => 4,
+        IPV6 => 16,
+        DOMAIN_NAME => usize::from(stream.read_u8().await?),
+        _ => {
+            return Err(Error::ServerError(
+                "Upstream SOCKS5 proxy replied with an unknown address type".to_string(),
+            ))
+        }
+    };
+    let mut bound_address = vec![0; address_length + 2];
+    stream.read_exact(&mut bound_address).await?;
+    Ok(())
+}
+
+/// Agree an authentication method with the client and carry it out. Clients
+/// must give a username and password that `authenticator` accepts, if there
+/// is one. Returns the principal the client authenticated as, if it had to.
+async fn authenticate(
+    stream: &mut TcpStream,
+    authenticator: Option<&dyn ProxyAuthenticator>,
+) -> Result<Option<String>, Error> {
+    if stream.read_u8().await? != VERSION {
+        return Err(Error::RequestError("Not a SOCKS5 client".to_string()));
+    }
+    let method_count = stream.read_u8().await?;
+    let mut methods = vec![0; usize::from(method_count)];
+    stream.read_exact(&mut methods).await?;
+
+    let method = if authenticator.is_some() {
+        USERNAME_PASSWORD
+    } else {
+        NO_AUTHENTICATION
+    };
+    if !methods.contains(&method) {
+        stream.write_all(&[VERSION, NO_ACCEPTABLE_METHODS]).await?;
+        return Err(Error::RequestError(
+            "SOCKS5 client offered no acceptable authentication method".to_string(),
+        ));
+    }
+    stream.write_all(&[VERSION, method]).await?;
+    let Some(authenticator) = authenticator else {
+        return Ok(None);
+    };
+
+    // RFC 1929
+    if stream.read_u8().await? != USERNAME_PASSWORD_VERSION {
+        return Err(Error::RequestError(
+            "Unsupported SOCKS5 username/password version".to_string(),
+        ));
+    }
+    let username = read_string(stream).await?;
+    let password = read_string(stream).await?;
+    if let Some(principal) = authenticator.authenticate(&username, &password) {
+        stream.write_all(&[USERNAME_PASSWORD_VERSION, 0]).await?;
+        Ok(Some(principal))
+    } else {
+        stream.write_all(&[USERNAME_PASSWORD_VERSION, 1]).await?;
+        Err(Error::RequestError(format!(
+            "SOCKS5 authentication failed for user {username}"
+        )))
+    }
+}
+
+/// Read the client's request. Returns the target host and port of a CONNECT,
+/// or the reply refusing any other request.
+async fn read_connect_request(
+    stream: &mut TcpStream,
+) -> Result<Result<(String, String), Reply>, Error> {
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).await?;
+    let [version, command, _, address_type] = header;
+    if version != VERSION {
+        return Err(Error::RequestError("Not a SOCKS5 request".to_string()));
+    }
+    let host = match address_type {
+        IPV4 => {
+            let mut octets = [0; 4];
+            stream.read_exact(&mut octets).await?;
+            Ipv4Addr::from(octets).to_string()
+        }
+        // Bracketed as the host of a CONNECT request would be
+        IPV6 => {
+            let mut octets = [0; 16];
+            stream.read_exact(&mut octets).await?;
+            format!("[{}]", Ipv6Addr::from(octets))
+        }
+        DOMAIN_NAME => read_string(stream).await?,
+        _ => return Ok(Err(Reply::AddressTypeNotSupported)),
+    };
+    let port = stream.read_u16().await?;
+    if command != CONNECT {
+        return Ok(Err(Reply::CommandNotSupported));
+    }
+    Ok(Ok((host, port.to_string())))
+}
+
+/// Read a string prefixed by its length in a single byte
+async fn read_string(stream: &mut TcpStream) -> Result<String, Error> {
+    let length = stream.read_u8().await?;
+    let mut bytes = vec![0; usize::from(length)];
+    stream.read_exact(&mut bytes).await?;
+    String::from_utf8(bytes).map_err(|e| Error::NonUtf8String(e.to_string()))
+}
+
+/// Append a string prefixed by its length in a single byte
+fn write_string(buffer: &mut Vec<u8>, string: &str) -> Result<(), Error> {
+    let length = u8::try_from(string.len())
+        .map_err(|_| Error::RequestError(format!("{string} is too long for SOCKS5")))?;
+    buffer.push(length);
+    buffer.extend_from_slice(string.as_bytes());
+    Ok(())
+}
+
+/// Answer the client's request. The bound address is left unspecified, as
+/// clients of a CONNECT have no use for it.
+async fn write_reply(stream: &mut TcpStream, reply: Reply) -> Result<(), Error> {
+    stream
+        .write_all(&[VERSION, reply as u8, 0, IPV4, 0, 0, 0, 0, 0, 0])
+        .await?;
+    Ok(())
+}
+
+fn reply_for(error: &Error) -> Reply {
+    match error {
+        Error::IOError(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+            Reply::ConnectionRefused
+        }
+        Error::IOError(_) => Reply::HostUnreachable,
+        _ => Reply::GeneralFailure,
+    }
+}
@@ -0,0 +1,139 @@
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+
+use super::{connection_info::ConnectionInfo, rewind::Rewind};
+use crate::error::Error;
+
+/// How much to read looking for the end of a HTTP request line
+const MAX_REQUEST_LINE_LENGTH: usize = 8192;
+/// What HTTP/2 clients with prior knowledge start with
+const HTTP2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// A decrypted byte stream handed to a `StreamInterceptor`
+pub trait DecryptedStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> DecryptedStream for S {}
+
+/// Handles intercepted tunnels that turn out not to carry HTTP once the
+/// client's TLS is stripped, such as SMTP submission or a custom RPC protocol.
+///
+/// `client` is the client's side of the tunnel and `upstream` a new TLS
+/// connection to the upstream, both already decrypted. The tunnel is closed
+/// once the returned future finishes.
+///
+/// Closures of the form `Fn(Box<dyn DecryptedStream>, Box<dyn DecryptedStream>,
+/// ConnectionInfo) -> BoxFuture<'static, Result<(), Error>>` implement this
+/// trait.
+pub trait StreamInterceptor: Send + Sync {
+    fn intercept(
+        &self,
+        client: Box<dyn DecryptedStream>,
+        upstream: Box<dyn DecryptedStream>,
+        info: ConnectionInfo,
+    ) -> BoxFuture<'_, Result<(), Error>>;
+}
+
+impl<F> StreamInterceptor for F
+where
+    F: Fn(
+            Box<dyn DecryptedStream>,
+            Box<dyn DecryptedStream>,
+            ConnectionInfo,
+        ) -> BoxFuture<'static, Result<(), Error>>
+        + Send
+        + Sync,
+{
+    fn intercept(
+        &self,
+        client: Box<dyn DecryptedStream>,
+        upstream: Box<dyn DecryptedStream>,
+        info: ConnectionInfo,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        self(client, upstream, info)
+    }
+}
+
+/// Splice the two streams together byte for byte, as if the proxy wasn't
+/// there. This is the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Splice;
+
+impl StreamInterceptor for Splice {
+    fn intercept(
+        &self,
+        mut client: Box<dyn DecryptedStream>,
+        mut upstream: Box<dyn DecryptedStream>,
+        _: ConnectionInfo,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async move {
+            tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+            Ok(())
+        })
+    }
+}
+
+/// Read enough of what the client sent once its TLS was stripped to tell
+/// whether it speaks HTTP. Returns the answer and the stream with what was
+/// read put back.
+///
+/// Only bytes that can't start a HTTP request make the answer no, unless
+/// `first_bytes_timeout` is given and the client sends nothing for that long,
+/// in which case it is taken to be waiting for the server to speak first.
+/// Should the client close its side before it can be told, the stream is
+/// left to the HTTP server to deal with.
+pub(crate) async fn sniff_http<S>(
+    mut stream: S,
+    first_bytes_timeout: Option<Duration>,
+) -> Result<(bool, Rewind<S>), Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 1024];
+    let is_http = loop {
+        match looks_like_http(&buffer) {
+            Some(is_http) => break is_http,
+            None if buffer.len() >= MAX_REQUEST_LINE_LENGTH => break false,
+            None => {}
+        }
+        let length = match first_bytes_timeout.filter(|_| buffer.is_empty()) {
+            Some(timeout) => match tokio::time::timeout(timeout, stream.read(&mut chunk)).await {
+                Ok(length) => length?,
+                Err(_) => break false,
+            },
+            None => stream.read(&mut chunk).await?,
+        };
+        if length == 0 {
+            break true;
+        }
+        buffer.extend_from_slice(&chunk[..length]);
+    };
+    Ok((is_http, Rewind::new(stream, buffer)))
+}
+
+/// Whether `bytes` start a HTTP request, or `None` if more are needed to tell
+fn looks_like_http(bytes: &[u8]) -> Option<bool> {
+    if bytes.is_empty() {
+        return None;
+    }
+    let preface = &HTTP2_PREFACE[..bytes.len().min(HTTP2_PREFACE.len())];
+    if bytes.starts_with(preface) {
+        return if preface.len() == HTTP2_PREFACE.len() {
+            Some(true)
+        } else {
+            None
+        };
+    }
+
+    // Only the request line is needed, so there is no room for any headers
+    let mut headers = [httparse::EMPTY_HEADER; 0];
+    let mut request = httparse::Request::new(&mut headers);
+    match request.parse(bytes) {
+        Ok(httparse::Status::Complete(_)) | Err(httparse::Error::TooManyHeaders) => Some(true),
+        Ok(httparse::Status::Partial) if request.version.is_some() => Some(true),
+        Ok(httparse::Status::Partial) => None,
+        Err(_) => Some(false),
+    }
+}
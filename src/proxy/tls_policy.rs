@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use openssl::sha::sha256;
+use openssl::x509::X509;
+
+use crate::error::Error;
+
+/// How the proxy decides whether to trust the certificate an upstream presents
+#[allow(clippy::type_complexity)]
+#[derive(Clone, Default)]
+pub enum UpstreamTlsPolicy {
+    /// Verify the certificate chain and host name against the system roots
+    /// and any `additional_root_certificates`
+    #[default]
+    Verify,
+    /// Accept any certificate, including self-signed, expired and mismatched
+    /// ones. Only for testing.
+    AcceptInvalid,
+    /// Accept a host's certificate only if the SHA-256 hash of its public key
+    /// (see `spki_sha256`) is one of the host's pins, whoever issued it. Hosts
+    /// without pins are verified as usual.
+    Pinned(HashMap<String, Vec<[u8; 32]>>),
+    /// Let a callback decide from the server name and the certificate
+    /// presented, in place of any other verification
+    Custom(Arc<dyn Fn(&str, &X509) -> bool + Send + Sync>),
+}
+
+/// How an upstream's certificate came to be trusted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpstreamVerification {
+    /// The chain and host name verified
+    Verified,
+    /// Nothing was checked, under `UpstreamTlsPolicy::AcceptInvalid`
+    Unchecked,
+    /// The public key matched one of the host's pins
+    Pinned,
+    /// The `UpstreamTlsPolicy::Custom` callback accepted it
+    Custom,
+    /// It was not trusted, and the client was shown a certificate it should
+    /// not trust either, as `InvalidUpstreamCertificate` asked
+    Failed,
+}
+
+/// What to tell the client when an upstream's certificate is not trusted
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InvalidUpstreamCertificate {
+    /// Refuse the CONNECT with a 502 Bad Gateway saying why
+    #[default]
+    BadGateway,
+    /// Open the tunnel but present a copy of the upstream's certificate signed
+    /// by its own key, leaving the client to decide whether to trust it
+    MirrorSelfSigned,
+    /// Open the tunnel but present a copy of the upstream's certificate that
+    /// has expired, leaving the client to decide whether to trust it
+    MirrorExpired,
+}
+
+impl UpstreamTlsPolicy {
+    /// Wrap a callback as a `UpstreamTlsPolicy::Custom`
+    pub fn custom<F>(accept: F) -> Self
+    where
+        F: Fn(&str, &X509) -> bool + Send + Sync + 'static,
+    {
+        Self::Custom(Arc::new(accept))
+    }
+
+    /// Whether the TLS library should verify the chain for `host` itself
+    pub(crate) fn verifies_chain(&self, host: &str) -> bool {
+        match self {
+            Self::Verify => true,
+            Self::Pinned(pins) => !pins.contains_key(host),
+            Self::AcceptInvalid | Self::Custom(_) => false,
+        }
+    }
+
+    /// Apply the checks the TLS library did not to the certificate `host`
+    /// presented
+    pub(crate) fn check(
+        &self,
+        host: &str,
+        certificate: &X509,
+    ) -> Result<UpstreamVerification, Error> {
+        match self {
+            Self::Verify => Ok(UpstreamVerification::Verified),
+            Self::AcceptInvalid => Ok(UpstreamVerification::Unchecked),
+            Self::Pinned(pins) => match pins.get(host) {
+                None => Ok(UpstreamVerification::Verified),
+                Some(hashes) if hashes.contains(&spki_sha256(certificate)?) => {
+                    Ok(UpstreamVerification::Pinned)
+                }
+                Some(_) => Err(Error::UpstreamCertificateRejected(format!(
+                    "public key of {host} matches none of its pins"
+                ))),
+            },
+            Self::Custom(accept) => {
+                if accept(host, certificate) {
+                    Ok(UpstreamVerification::Custom)
+                } else {
+                    Err(Error::UpstreamCertificateRejected(format!(
+                        "certificate of {host} refused by policy"
+                    )))
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Debug for UpstreamTlsPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Verify => f.write_str("Verify"),
+            Self::AcceptInvalid => f.write_str("AcceptInvalid"),
+            Self::Pinned(pins) => f.debug_tuple("Pinned").field(pins).finish(),
+            Self::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+/// The SHA-256 hash of a certificate's DER-encoded `SubjectPublicKeyInfo`, as
+/// used for pinning
+pub fn spki_sha256(certificate: &X509) -> Result<[u8; 32], Error> {
+    Ok(sha256(&certificate.public_key()?.public_key_to_der()?))
+}
@@ -0,0 +1,153 @@
+use std::net::SocketAddr;
+
+use http::Request;
+use hyper::{service::Service, Body};
+use tokio::net::TcpStream;
+use tower::Layer;
+
+use super::client_hello::peek_server_name;
+use super::connection_info::ConnectionInfo;
+use super::mitm::ThirdWheel;
+use super::upstream::Upstream;
+use super::{open_tunnel, run_mitm_on_connection, splice, ProxyState, Tunnel};
+use crate::error::Error;
+
+/// Handle a connection redirected to the proxy by the firewall. The client
+/// thinks it is talking to the upstream, so it starts straight in on the TLS
+/// handshake with no CONNECT request to say where it was going.
+pub(crate) async fn serve_transparent_connection<T, U>(
+    stream: TcpStream,
+    client_addr: SocketAddr,
+    state: ProxyState<T>,
+) -> Result<(), Error>
+where
+    T: Layer<ThirdWheel, Service = U> + Sync + std::marker::Send + 'static + Clone,
+    U: Service<Request<Body>, Response = <ThirdWheel as Service<Request<Body>>>::Response>
+        + Sync
+        + Send
+        + 'static
+        + Clone,
+    U::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    <U as Service<Request<Body>>>::Future: Send,
+{
+    let destination = original_destination(&stream);
+    let server_name = peek_server_name(&stream).await;
+    // Without a redirect there is no way to tell the port, so assume HTTPS's
+    let port = destination.map_or(443, |destination| destination.port());
+    let host = match (server_name, destination) {
+        (Some(server_name), _) => server_name,
+        (None, Some(destination)) => destination.ip().to_string(),
+        (None, None) => {
+            return Err(Error::RequestError(
+                "Transparent connection has neither an original destination nor an SNI".to_string(),
+            ))
+        }
+    };
+    let port = port.to_string();
+
+    let mut upstream = state.upstream_resolver.resolve(&host, &port);
+    // Unless the resolver rerouted it, dial the address the client itself was
+    // headed to rather than whatever the host name resolves to here
+    if let Some(destination) = destination {
+        if upstream == Upstream::new(&host, &port) {
+            upstream.host = destination.ip().to_string();
+        }
+    }
+    let info = ConnectionInfo::new(client_addr, format!("{host}:{port}"), upstream);
+    let intercept = state.intercept_filter.intercept(&host, &port, client_addr);
+
+    let tunnel = open_tunnel(
+        &state.spoofer,
+        &state.connector,
+        state.invalid_upstream_certificate,
+        intercept,
+        info,
+    )
+    .await?;
+    match tunnel {
+        Tunnel::Intercepted(tunnel) => {
+            run_mitm_on_connection(
+                stream,
+                *tunnel,
+                state.mitm,
+                state.connector,
+                &state.spoofer,
+                state.client_sni,
+                state.stream_interceptor,
+                state.server_speaks_first_timeout,
+            )
+            .await
+        }
+        Tunnel::Spliced(target_stream) => splice(stream, target_stream).await,
+    }
+}
+
+/// Where the client was connecting to before an iptables or ip6tables
+/// `REDIRECT` or `DNAT` rule sent it to the proxy. `None` if the connection
+/// was not redirected.
+#[cfg(target_os = "linux")]
+fn original_destination(stream: &TcpStream) -> Option<SocketAddr> {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+    let local_addr = stream.local_addr().ok()?;
+    // IPv4 clients of a dual-stack listener were redirected by iptables, so
+    // only native IPv6 connections have an ip6tables destination
+    let destination = match local_addr {
+        SocketAddr::V6(local) if local.ip().to_ipv4_mapped().is_none() => {
+            let address: libc::sockaddr_in6 =
+                socket_option(stream, libc::SOL_IPV6, libc::IP6T_SO_ORIGINAL_DST)?;
+            SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(address.sin6_addr.s6_addr),
+                u16::from_be(address.sin6_port),
+                address.sin6_flowinfo,
+                address.sin6_scope_id,
+            ))
+        }
+        _ => {
+            let address: libc::sockaddr_in =
+                socket_option(stream, libc::SOL_IP, libc::SO_ORIGINAL_DST)?;
+            SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr)),
+                u16::from_be(address.sin_port),
+            ))
+        }
+    };
+    // A connection made to the proxy directly has itself as its destination
+    if destination.ip().to_canonical() == local_addr.ip().to_canonical()
+        && destination.port() == local_addr.port()
+    {
+        return None;
+    }
+    Some(destination)
+}
+
+/// Read a socket option whose value is the plain old data `T`, such as a
+/// `sockaddr_in`
+#[cfg(target_os = "linux")]
+#[allow(unsafe_code)]
+fn socket_option<T>(stream: &TcpStream, level: libc::c_int, name: libc::c_int) -> Option<T> {
+    use std::convert::TryFrom;
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: the socket addresses read here are plain old data, for which
+    // all zeroes is valid
+    let mut value: T = unsafe { std::mem::zeroed() };
+    let mut length = libc::socklen_t::try_from(std::mem::size_of::<T>()).ok()?;
+    // SAFETY: the pointers are to live values, and `length` tells the kernel
+    // how much it may write to `value`
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            level,
+            name,
+            std::ptr::addr_of_mut!(value).cast(),
+            std::ptr::addr_of_mut!(length),
+        )
+    };
+    (result == 0).then_some(value)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn original_destination(_: &TcpStream) -> Option<SocketAddr> {
+    None
+}
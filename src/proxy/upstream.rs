@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+
+use super::upstream_proxy::UpstreamProxy;
+
+/// The server that the proxy should actually connect to for a CONNECT request.
+///
+/// `host` and `port` are used for the TCP connection (with `host` looked up
+/// by the proxy's `Resolver`) and `sni` is the server name sent
+/// in the TLS handshake and used to verify the upstream certificate. If
+/// `proxy` is set, the connection is made through that proxy in place of any
+/// `MitmProxyBuilder::upstream_proxy`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Upstream {
+    pub host: String,
+    pub port: String,
+    pub sni: String,
+    pub proxy: Option<UpstreamProxy>,
+}
+
+impl Upstream {
+    /// An upstream whose SNI is the same as the host it connects to
+    #[must_use]
+    pub fn new(host: &str, port: &str) -> Self {
+        Self {
+            host: host.to_string(),
+            port: port.to_string(),
+            sni: host.to_string(),
+            proxy: None,
+        }
+    }
+
+    /// Override the server name sent in the TLS handshake
+    #[must_use]
+    pub fn with_sni(mut self, sni: &str) -> Self {
+        self.sni = sni.to_string();
+        self
+    }
+
+    /// Connect through another proxy
+    #[must_use]
+    pub fn with_proxy(mut self, proxy: UpstreamProxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+}
+
+/// Maps the `(host, port)` of a CONNECT request to the upstream the proxy
+/// should dial.
+///
+/// Closures of the form `Fn(&str, &str) -> Upstream` implement this trait so
+/// simple rewrites don't need their own type.
+pub trait UpstreamResolver: Send + Sync {
+    fn resolve(&self, host: &str, port: &str) -> Upstream;
+}
+
+impl<F> UpstreamResolver for F
+where
+    F: Fn(&str, &str) -> Upstream + Send + Sync,
+{
+    fn resolve(&self, host: &str, port: &str) -> Upstream {
+        self(host, port)
+    }
+}
+
+/// Connect to whatever the client asked for. This is the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Passthrough;
+
+impl UpstreamResolver for Passthrough {
+    fn resolve(&self, host: &str, port: &str) -> Upstream {
+        Upstream::new(host, port)
+    }
+}
+
+/// Send every tunnel to the same upstream regardless of the CONNECT target
+#[derive(Clone, Debug)]
+pub struct StaticUpstream(pub Upstream);
+
+impl UpstreamResolver for StaticUpstream {
+    fn resolve(&self, _: &str, _: &str) -> Upstream {
+        self.0.clone()
+    }
+}
+
+/// A table of rewrites keyed by CONNECT host. A rule keyed by `host:port`
+/// takes priority over one keyed by `host` alone; hosts without a rule are
+/// passed through unchanged.
+#[derive(Clone, Debug, Default)]
+pub struct UpstreamRules {
+    rules: HashMap<String, Upstream>,
+}
+
+impl UpstreamRules {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a rule for `from`, which is either a bare host or `host:port`
+    #[must_use]
+    pub fn rule(mut self, from: &str, to: Upstream) -> Self {
+        self.rules.insert(from.to_string(), to);
+        self
+    }
+}
+
+impl UpstreamResolver for UpstreamRules {
+    fn resolve(&self, host: &str, port: &str) -> Upstream {
+        self.rules
+            .get(&format!("{host}:{port}"))
+            .or_else(|| self.rules.get(host))
+            .cloned()
+            .unwrap_or_else(|| Upstream::new(host, port))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules() -> UpstreamRules {
+        UpstreamRules::new()
+            .rule("example.com", Upstream::new("host-rule", "443"))
+            .rule("example.com:8443", Upstream::new("host-port-rule", "443"))
+    }
+
+    #[test]
+    fn host_and_port_rule_beats_host_rule() {
+        assert_eq!(
+            rules().resolve("example.com", "8443"),
+            Upstream::new("host-port-rule", "443")
+        );
+    }
+
+    #[test]
+    fn host_rule_applies_to_other_ports() {
+        assert_eq!(
+            rules().resolve("example.com", "443"),
+            Upstream::new("host-rule", "443")
+        );
+    }
+
+    #[test]
+    fn hosts_without_a_rule_pass_through() {
+        assert_eq!(
+            rules().resolve("example.org", "8443"),
+            Upstream::new("example.org", "8443")
+        );
+    }
+
+    #[test]
+    fn host_and_port_rule_only_applies_to_its_port() {
+        let rules = UpstreamRules::new().rule("example.com:8443", Upstream::new("rule", "443"));
+        assert_eq!(
+            rules.resolve("example.com", "443"),
+            Upstream::new("example.com", "443")
+        );
+    }
+}
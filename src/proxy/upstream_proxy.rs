@@ -0,0 +1,171 @@
+use std::fmt;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use super::socks5;
+use crate::error::Error;
+
+/// The most a CONNECT response's head may take up
+const MAX_RESPONSE_HEAD_LENGTH: usize = 8192;
+
+/// Another proxy that the proxy's connections to upstreams are made through,
+/// for chaining proxies or reaching networks only a proxy can
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum UpstreamProxy {
+    /// An HTTP proxy at `address`, given as `host:port`, which is sent a
+    /// CONNECT request for each connection
+    Http {
+        address: String,
+        /// A username and password to send with Basic authentication
+        credentials: Option<(String, String)>,
+    },
+    /// A SOCKS5 proxy at `address`, given as `host:port`, which is asked to
+    /// resolve upstream host names itself
+    Socks5 {
+        address: String,
+        /// A username and password to authenticate with
+        credentials: Option<(String, String)>,
+    },
+}
+
+impl UpstreamProxy {
+    /// An HTTP proxy at `address`, given as `host:port`
+    #[must_use]
+    pub fn http(address: &str) -> Self {
+        Self::Http {
+            address: address.to_string(),
+            credentials: None,
+        }
+    }
+
+    /// A SOCKS5 proxy at `address`, given as `host:port`
+    #[must_use]
+    pub fn socks5(address: &str) -> Self {
+        Self::Socks5 {
+            address: address.to_string(),
+            credentials: None,
+        }
+    }
+
+    /// Authenticate to the proxy with a username and password
+    #[must_use]
+    pub fn with_credentials(mut self, username: &str, password: &str) -> Self {
+        match &mut self {
+            Self::Http { credentials, .. } | Self::Socks5 { credentials, .. } => {
+                *credentials = Some((username.to_string(), password.to_string()));
+            }
+        }
+        self
+    }
+
+    /// Open a connection to `host:port` through the proxy. The proxy is left
+    /// to resolve `host`.
+    pub(crate) async fn connect(&self, host: &str, port: &str) -> Result<TcpStream, Error> {
+        match self {
+            Self::Http {
+                address,
+                credentials,
+            } => {
+                let mut stream = TcpStream::connect(address).await?;
+                http_connect(&mut stream, host, port, credentials.as_ref()).await?;
+                Ok(stream)
+            }
+            Self::Socks5 {
+                address,
+                credentials,
+            } => {
+                let mut stream = TcpStream::connect(address).await?;
+                socks5::connect_through(&mut stream, host, port, credentials.as_ref()).await?;
+                Ok(stream)
+            }
+        }
+    }
+}
+
+/// Leaves out the password, as upstreams end up in every request's
+/// `ConnectionInfo` and so in its logs
+impl fmt::Debug for UpstreamProxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (name, address, credentials) = match self {
+            Self::Http {
+                address,
+                credentials,
+            } => ("Http", address, credentials),
+            Self::Socks5 {
+                address,
+                credentials,
+            } => ("Socks5", address, credentials),
+        };
+        f.debug_struct(name)
+            .field("address", address)
+            .field(
+                "credentials",
+                &credentials
+                    .as_ref()
+                    .map(|(username, _)| (username, "<redacted>")),
+            )
+            .finish()
+    }
+}
+
+/// Ask the HTTP proxy on the other end of `stream` for a tunnel to
+/// `host:port`
+async fn http_connect(
+    stream: &mut TcpStream,
+    host: &str,
+    port: &str,
+    credentials: Option<&(String, String)>,
+) -> Result<(), Error> {
+    let mut request = format!("CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n");
+    if let Some((username, password)) = credentials {
+        request.push_str("Proxy-Authorization: Basic ");
+        request.push_str(&openssl::base64::encode_block(
+            format!("{username}:{password}").as_bytes(),
+        ));
+        request.push_str("\r\n");
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read a byte at a time so nothing the upstream sends after the response
+    // is taken from the tunnel
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() == MAX_RESPONSE_HEAD_LENGTH {
+            return Err(Error::ServerError(
+                "Upstream proxy's response to CONNECT is too long".to_string(),
+            ));
+        }
+        head.push(stream.read_u8().await?);
+    }
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut response = httparse::Response::new(&mut headers);
+    response
+        .parse(&head)
+        .map_err(|e| Error::ServerError(format!("Bad response from upstream proxy: {e}")))?;
+    match response.code {
+        Some(200) => Ok(()),
+        code => Err(Error::ServerError(format!(
+            "Upstream proxy refused CONNECT to {host}:{port} with status {code:?}"
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_output_shows_the_username_but_not_the_password() {
+        let proxy = UpstreamProxy::http("127.0.0.1:8080").with_credentials("alice", "wonderland");
+        assert_eq!(
+            format!("{proxy:?}"),
+            r#"Http { address: "127.0.0.1:8080", credentials: Some(("alice", "<redacted>")) }"#
+        );
+        assert_eq!(
+            format!("{:?}", UpstreamProxy::socks5("127.0.0.1:1080")),
+            r#"Socks5 { address: "127.0.0.1:1080", credentials: None }"#
+        );
+    }
+}
@@ -0,0 +1,311 @@
+use std::convert::TryFrom;
+use std::io::ErrorKind;
+use std::sync::Arc;
+
+use http::{
+    header::{SEC_WEBSOCKET_EXTENSIONS, UPGRADE},
+    Request, Response, StatusCode,
+};
+use hyper::upgrade::{OnUpgrade, Upgraded};
+use hyper::Body;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::error::Error;
+
+/// The most the proxy will buffer of one message to hand an interceptor
+const MAX_MESSAGE_LENGTH: usize = 64 << 20;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+
+/// Lengths that say the real length follows in the next 2 or 8 bytes
+const LENGTH_16: u8 = 126;
+const LENGTH_64: u8 = 127;
+
+/// A whole text or binary WebSocket message, put back together from however
+/// many frames it was sent in
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WebSocketMessage {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// Which way a WebSocket message is going
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WebSocketDirection {
+    ToServer,
+    ToClient,
+}
+
+/// Sees the text and binary messages sent either way on WebSocket connections
+/// made through the proxy. Ping, pong and close frames are passed on as they
+/// are.
+///
+/// The messages `intercept` returns are sent on in `direction` in place of
+/// `message`: the message itself to pass it on, a different one to modify it,
+/// none to drop it, or several to inject messages of its own.
+///
+/// Closures of the form
+/// `Fn(WebSocketDirection, WebSocketMessage) -> Vec<WebSocketMessage>`
+/// implement this trait.
+pub trait WebSocketInterceptor: Send + Sync {
+    fn intercept(
+        &self,
+        direction: WebSocketDirection,
+        message: WebSocketMessage,
+    ) -> Vec<WebSocketMessage>;
+}
+
+impl<F> WebSocketInterceptor for F
+where
+    F: Fn(WebSocketDirection, WebSocketMessage) -> Vec<WebSocketMessage> + Send + Sync,
+{
+    fn intercept(
+        &self,
+        direction: WebSocketDirection,
+        message: WebSocketMessage,
+    ) -> Vec<WebSocketMessage> {
+        self(direction, message)
+    }
+}
+
+/// The client's end of the upgrade `request` asks for, if it asks for one
+pub(crate) fn take_upgrade(request: &mut Request<Body>) -> Option<OnUpgrade> {
+    if request.headers().contains_key(UPGRADE) {
+        request.extensions_mut().remove::<OnUpgrade>()
+    } else {
+        None
+    }
+}
+
+/// `interceptor`, if `request` is a WebSocket upgrade, with `request` readied
+/// for its messages to be intercepted
+pub(crate) fn interceptor_for(
+    request: &mut Request<Body>,
+    interceptor: Option<Arc<dyn WebSocketInterceptor>>,
+) -> Option<Arc<dyn WebSocketInterceptor>> {
+    let protocol = request
+        .headers()
+        .get(UPGRADE)
+        .and_then(|protocol| protocol.to_str().ok());
+    if !matches!(protocol, Some(protocol) if protocol.eq_ignore_ascii_case("websocket")) {
+        return None;
+    }
+    let interceptor = interceptor?;
+    // Extensions such as permessage-deflate would hide the messages
+    request.headers_mut().remove(SEC_WEBSOCKET_EXTENSIONS);
+    Some(interceptor)
+}
+
+/// If the upstream agreed to the upgrade, join its connection to the client's
+/// once both have switched protocols. WebSocket messages go through
+/// `interceptor` if there is one; anything else is spliced byte for byte.
+pub(crate) fn splice_upgrade(
+    mut response: Response<Body>,
+    client: OnUpgrade,
+    interceptor: Option<Arc<dyn WebSocketInterceptor>>,
+) -> Response<Body> {
+    if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        return response;
+    }
+    let server = hyper::upgrade::on(&mut response);
+    tokio::spawn(async move {
+        let result = match futures::try_join!(client, server) {
+            Ok((client, server)) => splice(client, server, interceptor).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            log::error!("Upgraded connection failed: {e}");
+        }
+    });
+    response
+}
+
+async fn splice(
+    mut client: Upgraded,
+    mut server: Upgraded,
+    interceptor: Option<Arc<dyn WebSocketInterceptor>>,
+) -> Result<(), Error> {
+    if let Some(interceptor) = interceptor {
+        let (client_reader, client_writer) = tokio::io::split(client);
+        let (server_reader, server_writer) = tokio::io::split(server);
+        let (to_server, to_client) = futures::join!(
+            relay(
+                client_reader,
+                server_writer,
+                WebSocketDirection::ToServer,
+                &*interceptor
+            ),
+            relay(
+                server_reader,
+                client_writer,
+                WebSocketDirection::ToClient,
+                &*interceptor
+            ),
+        );
+        return to_server.and(to_client);
+    }
+    tokio::io::copy_bidirectional(&mut client, &mut server).await?;
+    Ok(())
+}
+
+/// Pass the frames from `reader` to `writer` until `reader` closes, handing
+/// each whole message to `interceptor` on the way
+async fn relay<R, W>(
+    mut reader: R,
+    mut writer: W,
+    direction: WebSocketDirection,
+    interceptor: &dyn WebSocketInterceptor,
+) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    // Clients must mask their frames and servers must not
+    let masked = direction == WebSocketDirection::ToServer;
+    let mut fragments: Option<(u8, Vec<u8>)> = None;
+    while let Some(frame) = read_frame(&mut reader).await? {
+        // Control frames can come between the fragments of a message
+        if frame.opcode >= CLOSE {
+            write_frame(&mut writer, frame.opcode, &frame.payload, masked).await?;
+            continue;
+        }
+        let (opcode, payload) = match (frame.opcode, fragments.take()) {
+            (CONTINUATION, Some((opcode, mut payload))) => {
+                payload.extend_from_slice(&frame.payload);
+                (opcode, payload)
+            }
+            (TEXT | BINARY, None) => (frame.opcode, frame.payload),
+            (opcode, _) => {
+                return Err(Error::WebSocketError(format!(
+                    "Unexpected frame with opcode {opcode}"
+                )))
+            }
+        };
+        if payload.len() > MAX_MESSAGE_LENGTH {
+            return Err(Error::WebSocketError(
+                "Message is too long to intercept".to_string(),
+            ));
+        }
+        if !frame.fin {
+            fragments = Some((opcode, payload));
+            continue;
+        }
+
+        let message = if opcode == TEXT {
+            WebSocketMessage::Text(String::from_utf8(payload).map_err(|_| {
+                Error::WebSocketError("Text message is not valid UTF-8".to_string())
+            })?)
+        } else {
+            WebSocketMessage::Binary(payload)
+        };
+        for message in interceptor.intercept(direction, message) {
+            match message {
+                WebSocketMessage::Text(text) => {
+                    write_frame(&mut writer, TEXT, text.as_bytes(), masked).await?;
+                }
+                WebSocketMessage::Binary(bytes) => {
+                    write_frame(&mut writer, BINARY, &bytes, masked).await?;
+                }
+            }
+        }
+    }
+    writer.shutdown().await?;
+    Ok(())
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Read the next frame, unmasked. Returns `None` if the stream closed cleanly
+/// between frames.
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Frame>, Error> {
+    let mut header = [0; 2];
+    match reader.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    // No extensions are negotiated, so none of the reserved bits may be set
+    if header[0] & 0x70 != 0 {
+        return Err(Error::WebSocketError(
+            "Frame uses an unknown extension".to_string(),
+        ));
+    }
+    let length = match header[1] & 0x7f {
+        LENGTH_16 => u64::from(reader.read_u16().await?),
+        LENGTH_64 => reader.read_u64().await?,
+        length => u64::from(length),
+    };
+    let length = usize::try_from(length)
+        .ok()
+        .filter(|length| *length <= MAX_MESSAGE_LENGTH)
+        .ok_or_else(|| Error::WebSocketError("Frame is too long to intercept".to_string()))?;
+    let mask = if header[1] & 0x80 == 0 {
+        None
+    } else {
+        let mut mask = [0; 4];
+        reader.read_exact(&mut mask).await?;
+        Some(mask)
+    };
+    let mut payload = vec![0; length];
+    reader.read_exact(&mut payload).await?;
+    if let Some(mask) = mask {
+        apply_mask(&mut payload, mask);
+    }
+    Ok(Some(Frame {
+        fin: header[0] & 0x80 != 0,
+        opcode: header[0] & 0x0f,
+        payload,
+    }))
+}
+
+/// Write `payload` as a single, final frame
+async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    opcode: u8,
+    payload: &[u8],
+    masked: bool,
+) -> Result<(), Error> {
+    let mask_bit = if masked { 0x80 } else { 0 };
+    let mut frame = vec![0x80 | opcode];
+    match (u8::try_from(payload.len()), u16::try_from(payload.len())) {
+        (Ok(length), _) if length < LENGTH_16 => frame.push(mask_bit | length),
+        (_, Ok(length)) => {
+            frame.push(mask_bit | LENGTH_16);
+            frame.extend_from_slice(&length.to_be_bytes());
+        }
+        _ => {
+            frame.push(mask_bit | LENGTH_64);
+            frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        }
+    }
+    let mut payload = payload.to_vec();
+    if masked {
+        let mask = masking_key()?;
+        frame.extend_from_slice(&mask);
+        apply_mask(&mut payload, mask);
+    }
+    frame.extend_from_slice(&payload);
+    writer.write_all(&frame).await?;
+    Ok(())
+}
+
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (byte, mask) in payload.iter_mut().zip(mask.iter().cycle()) {
+        *byte ^= mask;
+    }
+}
+
+/// A fresh key from OpenSSL's CSPRNG, as RFC 6455 requires masking keys to
+/// be unpredictable
+fn masking_key() -> Result<[u8; 4], Error> {
+    let mut key = [0; 4];
+    openssl::rand::rand_bytes(&mut key)?;
+    Ok(key)
+}
@@ -0,0 +1,21 @@
+//! The TLS backend. Handshakes go through native-tls with the default
+//! `native-tls` feature, or through rustls with the `rustls` feature, which
+//! takes precedence if both are enabled; either way certificates are forged
+//! with OpenSSL.
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+compile_error!("third-wheel needs a TLS backend: enable the `native-tls` or `rustls` feature");
+
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+mod native_backend;
+#[cfg(feature = "rustls")]
+mod rustls_backend;
+
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+pub(crate) use self::native_backend::{
+    accept, is_verification_failure, server_identity, ClientTlsStream, ServerIdentity, TlsConnector,
+};
+#[cfg(feature = "rustls")]
+pub(crate) use self::rustls_backend::{
+    accept, is_verification_failure, server_identity, ClientTlsStream, ServerIdentity, TlsConnector,
+};
@@ -0,0 +1,142 @@
+use std::os::raw::c_int;
+
+use openssl::error::ErrorStack;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::{PKey, Private};
+use openssl::stack::Stack;
+use openssl::x509::X509;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::certificates::ClientIdentity;
+use crate::error::Error;
+
+/// What clients are shown in place of an upstream's certificate
+pub(crate) type ServerIdentity = native_tls::Identity;
+pub(crate) type ServerTlsStream<S> = tokio_native_tls::TlsStream<S>;
+pub(crate) type ClientTlsStream<S> = tokio_native_tls::TlsStream<S>;
+
+pub(crate) fn server_identity(
+    certificate: &X509,
+    key: &PKey<Private>,
+) -> Result<ServerIdentity, Error> {
+    let pkcs = Pkcs12::builder()
+        .pkey(key)
+        .cert(certificate)
+        .build2("third-wheel")?
+        .to_der()?;
+    let identity = native_tls::Identity::from_pkcs12(&pkcs, "third-wheel")?;
+    Ok(identity)
+}
+
+/// Accept a client's handshake as `identity`, offering it the `alpn`
+/// protocols. Returns the stream and the protocol agreed on.
+pub(crate) async fn accept<S>(
+    stream: S,
+    identity: ServerIdentity,
+    alpn: &[&str],
+) -> Result<(ServerTlsStream<S>, Option<Vec<u8>>), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let acceptor = tokio_native_tls::TlsAcceptor::from(
+        native_tls::TlsAcceptor::builder(identity)
+            .accept_alpn(alpn)
+            .build()?,
+    );
+    let stream = acceptor.accept(stream).await?;
+    let protocol = stream.get_ref().negotiated_alpn()?;
+    Ok((stream, protocol))
+}
+
+/// OpenSSL's `ERR_LIB_SSL` and `SSL_R_CERTIFICATE_VERIFY_FAILED`, which a
+/// handshake fails with when the server's certificate isn't trusted
+const ERR_LIB_SSL: c_int = 20;
+const SSL_R_CERTIFICATE_VERIFY_FAILED: c_int = 134;
+
+/// Whether `error` came from a TLS handshake that failed because the server's
+/// certificate couldn't be verified. native-tls hides why a handshake failed,
+/// so this looks for OpenSSL's own error beneath it and is never true with
+/// native-tls's other backends.
+pub(crate) fn is_verification_failure(error: &Error) -> bool {
+    match error {
+        Error::NativeTlsError(error) => std::error::Error::source(error)
+            .and_then(|source| source.downcast_ref::<ErrorStack>())
+            .is_some_and(|stack| {
+                stack.errors().iter().any(|e| {
+                    e.library_code() == ERR_LIB_SSL
+                        && e.reason_code() == SSL_R_CERTIFICATE_VERIFY_FAILED
+                })
+            }),
+        _ => false,
+    }
+}
+
+/// Makes TLS connections to servers, trusting the system's root certificates
+/// and any others it is given
+#[derive(Clone)]
+pub(crate) struct TlsConnector {
+    root_certificates: Vec<X509>,
+}
+
+impl TlsConnector {
+    pub(crate) const fn new(root_certificates: Vec<X509>) -> Self {
+        Self { root_certificates }
+    }
+
+    /// Carry out the handshake with `server_name`, offering the `alpn`
+    /// protocols and presenting `identity` if the server asks for a
+    /// certificate. The server's certificate chain is only verified if
+    /// `verify_chain` is set. Returns the stream, the server's certificate and
+    /// the protocol agreed on.
+    pub(crate) async fn connect<S>(
+        &self,
+        stream: S,
+        server_name: &str,
+        identity: Option<&ClientIdentity>,
+        verify_chain: bool,
+        alpn: &[&str],
+    ) -> Result<(ClientTlsStream<S>, X509, Option<Vec<u8>>), Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut connector = native_tls::TlsConnector::builder();
+        for root_certificate in &self.root_certificates {
+            connector.add_root_certificate(native_tls::Certificate::from_der(
+                &root_certificate.to_der()?,
+            )?);
+        }
+        if let Some(identity) = identity {
+            connector.identity(client_identity(identity)?);
+        }
+        if !verify_chain {
+            connector.danger_accept_invalid_certs(true);
+        }
+        connector.request_alpns(alpn);
+        let connector = tokio_native_tls::TlsConnector::from(connector.build()?);
+        let stream = connector.connect(server_name, stream).await?;
+
+        // TODO: Currently to copy the certificate we do a round trip from one library -> der -> other library. This is inefficient, it should be possible to do it better some how.
+        let Some(certificate) = stream.get_ref().peer_certificate()? else {
+            return Err(Error::ServerError(
+                "Server did not provide a certificate for TLS connection".to_string(),
+            ));
+        };
+        let certificate = X509::from_der(&certificate.to_der()?)?;
+        let protocol = stream.get_ref().negotiated_alpn()?;
+        Ok((stream, certificate, protocol))
+    }
+}
+
+fn client_identity(identity: &ClientIdentity) -> Result<native_tls::Identity, Error> {
+    let mut chain = Stack::new()?;
+    for intermediate in &identity.chain {
+        chain.push(intermediate.clone())?;
+    }
+    let pkcs = Pkcs12::builder()
+        .pkey(&identity.key)
+        .cert(&identity.certificate)
+        .ca(chain)
+        .build2("third-wheel")?
+        .to_der()?;
+    Ok(native_tls::Identity::from_pkcs12(&pkcs, "third-wheel")?)
+}
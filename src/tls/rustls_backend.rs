@@ -0,0 +1,247 @@
+use std::convert::TryFrom;
+use std::io;
+use std::sync::{Arc, OnceLock};
+
+use openssl::pkey::{PKey, Private};
+use openssl::x509::X509;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::certificates::ClientIdentity;
+use crate::error::Error;
+
+/// What clients are shown in place of an upstream's certificate. The forged
+/// leaf is handed to rustls as it is, with its key already loaded.
+pub(crate) type ServerIdentity = Arc<CertifiedKey>;
+pub(crate) type ServerTlsStream<S> = tokio_rustls::server::TlsStream<S>;
+pub(crate) type ClientTlsStream<S> = tokio_rustls::client::TlsStream<S>;
+
+pub(crate) fn server_identity(
+    certificate: &X509,
+    key: &PKey<Private>,
+) -> Result<ServerIdentity, Error> {
+    let key = provider()
+        .key_provider
+        .load_private_key(PrivateKeyDer::Pkcs8(key.private_key_to_pkcs8()?.into()))?;
+    Ok(Arc::new(CertifiedKey::new(
+        vec![CertificateDer::from(certificate.to_der()?)],
+        key,
+    )))
+}
+
+/// Accept a client's handshake as `identity`, offering it the `alpn`
+/// protocols. Returns the stream and the protocol agreed on.
+pub(crate) async fn accept<S>(
+    stream: S,
+    identity: ServerIdentity,
+    alpn: &[&str],
+) -> Result<(ServerTlsStream<S>, Option<Vec<u8>>), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(SingleIdentity(identity)));
+    config.alpn_protocols = alpn_protocols(alpn);
+    let stream = tokio_rustls::TlsAcceptor::from(Arc::new(config))
+        .accept(stream)
+        .await
+        .map_err(handshake_error)?;
+    let protocol = stream.get_ref().1.alpn_protocol().map(<[u8]>::to_vec);
+    Ok((stream, protocol))
+}
+
+/// Whether `error` came from a TLS handshake that failed because the server's
+/// certificate couldn't be verified
+pub(crate) const fn is_verification_failure(error: &Error) -> bool {
+    matches!(
+        error,
+        Error::RustlsError(rustls::Error::InvalidCertificate(_))
+    )
+}
+
+/// Makes TLS connections to servers, trusting the system's root certificates
+/// and any others it is given
+#[derive(Clone)]
+pub(crate) struct TlsConnector {
+    roots: Arc<RootCertStore>,
+}
+
+impl TlsConnector {
+    pub(crate) fn new(root_certificates: Vec<X509>) -> Self {
+        let mut roots = RootCertStore::empty();
+        roots.add_parsable_certificates(native_roots().iter().cloned());
+        for root_certificate in root_certificates {
+            match root_certificate.to_der() {
+                Ok(der) => {
+                    if let Err(e) = roots.add(CertificateDer::from(der)) {
+                        log::warn!("Ignoring unusable root certificate: {e}");
+                    }
+                }
+                Err(e) => log::warn!("Ignoring unusable root certificate: {e}"),
+            }
+        }
+        Self {
+            roots: Arc::new(roots),
+        }
+    }
+
+    /// Carry out the handshake with `server_name`, offering the `alpn`
+    /// protocols and presenting `identity` if the server asks for a
+    /// certificate. The server's certificate chain is only verified if
+    /// `verify_chain` is set. Returns the stream, the server's certificate and
+    /// the protocol agreed on.
+    pub(crate) async fn connect<S>(
+        &self,
+        stream: S,
+        server_name: &str,
+        identity: Option<&ClientIdentity>,
+        verify_chain: bool,
+        alpn: &[&str],
+    ) -> Result<(ClientTlsStream<S>, X509, Option<Vec<u8>>), Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let config = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?;
+        let config = if verify_chain {
+            config.with_root_certificates(Arc::clone(&self.roots))
+        } else {
+            config
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate(provider())))
+        };
+        let mut config = match identity {
+            Some(identity) => {
+                let mut chain = vec![CertificateDer::from(identity.certificate.to_der()?)];
+                for intermediate in &identity.chain {
+                    chain.push(CertificateDer::from(intermediate.to_der()?));
+                }
+                let key = PrivateKeyDer::Pkcs8(identity.key.private_key_to_pkcs8()?.into());
+                config.with_client_auth_cert(chain, key)?
+            }
+            None => config.with_no_client_auth(),
+        };
+        config.alpn_protocols = alpn_protocols(alpn);
+
+        let name = ServerName::try_from(server_name.to_string())
+            .map_err(|_| Error::RequestError(format!("Invalid server name {server_name}")))?;
+        let stream = tokio_rustls::TlsConnector::from(Arc::new(config))
+            .connect(name, stream)
+            .await
+            .map_err(handshake_error)?;
+
+        let (_, connection) = stream.get_ref();
+        let certificate = match connection.peer_certificates().and_then(<[_]>::first) {
+            Some(certificate) => X509::from_der(certificate)?,
+            None => {
+                return Err(Error::ServerError(
+                    "Server did not provide a certificate for TLS connection".to_string(),
+                ))
+            }
+        };
+        let protocol = connection.alpn_protocol().map(<[u8]>::to_vec);
+        Ok((stream, certificate, protocol))
+    }
+}
+
+/// Only the ring provider is enabled, so static musl builds need no C++ or
+/// cmake toolchain. Made once and shared by every handshake.
+fn provider() -> Arc<CryptoProvider> {
+    static PROVIDER: OnceLock<Arc<CryptoProvider>> = OnceLock::new();
+    Arc::clone(PROVIDER.get_or_init(|| Arc::new(rustls::crypto::ring::default_provider())))
+}
+
+/// The system's root certificates, loaded once
+fn native_roots() -> &'static [CertificateDer<'static>] {
+    static ROOTS: OnceLock<Vec<CertificateDer<'static>>> = OnceLock::new();
+    ROOTS.get_or_init(|| {
+        let loaded = rustls_native_certs::load_native_certs();
+        for e in &loaded.errors {
+            log::warn!("Failed to load a system root certificate: {e}");
+        }
+        loaded.certs
+    })
+}
+
+fn alpn_protocols(alpn: &[&str]) -> Vec<Vec<u8>> {
+    alpn.iter()
+        .map(|protocol| protocol.as_bytes().to_vec())
+        .collect()
+}
+
+/// tokio-rustls reports handshake failures as IO errors wrapping the rustls
+/// error, which is unwrapped so it can be told apart from other IO errors
+fn handshake_error(error: io::Error) -> Error {
+    let rustls_error = error
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<rustls::Error>())
+        .cloned();
+    rustls_error.map_or_else(|| Error::IOError(error), Error::RustlsError)
+}
+
+/// Presents the same forged certificate whatever the client asks for
+#[derive(Debug)]
+struct SingleIdentity(ServerIdentity);
+
+impl ResolvesServerCert for SingleIdentity {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(Arc::clone(&self.0))
+    }
+}
+
+/// Trusts any certificate, as native-tls's `danger_accept_invalid_certs`
+/// does, while still checking the handshake is signed by its key
+#[derive(Debug)]
+struct AcceptAnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _: &CertificateDer<'_>,
+        _: &[CertificateDer<'_>],
+        _: &ServerName<'_>,
+        _: &[u8],
+        _: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            certificate,
+            signature,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            certificate,
+            signature,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
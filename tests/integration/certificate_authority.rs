@@ -0,0 +1,69 @@
+use third_wheel::*;
+
+use crate::harness::TestDir;
+
+#[test]
+fn generated_ca_round_trips_through_pem_files() {
+    let dir = TestDir::new();
+    let cert_file = dir.file("cert.pem");
+    let key_file = dir.file("key.pem");
+
+    let ca =
+        CertificateAuthority::generate("/O=third-wheel/CN=ca.example.com", KeyType::Rsa2048, 30)
+            .unwrap();
+    ca.save_pem_files(&cert_file, &key_file).unwrap();
+    let loaded = CertificateAuthority::load_from_pem_files(&cert_file, &key_file).unwrap();
+
+    assert_eq!(loaded.cert.to_der().unwrap(), ca.cert.to_der().unwrap());
+    assert!(loaded.key.public_eq(&ca.key));
+}
+
+#[test]
+fn generated_ca_round_trips_through_encrypted_pem_files() {
+    let dir = TestDir::new();
+    let cert_file = dir.file("cert.pem");
+    let key_file = dir.file("key.pem");
+
+    let ca = CertificateAuthority::generate("/CN=ca.example.com", KeyType::Rsa2048, 30).unwrap();
+    ca.save_pem_files_with_passphrase_on_key(&cert_file, &key_file, "third-wheel")
+        .unwrap();
+    let loaded = CertificateAuthority::load_from_pem_files_with_passphrase_on_key(
+        &cert_file,
+        &key_file,
+        "third-wheel",
+    )
+    .unwrap();
+
+    assert!(loaded.key.public_eq(&ca.key));
+}
+
+#[test]
+fn malformed_subject_is_rejected() {
+    assert!(matches!(
+        CertificateAuthority::generate("/CN", KeyType::EcdsaP256, 30),
+        Err(Error::InvalidSubject(_))
+    ));
+}
+
+#[test]
+fn ec_ca_round_trips_through_der_and_pkcs12_files() {
+    let dir = TestDir::new();
+    let cert_file = dir.file("cert.der");
+    let key_file = dir.file("key.der");
+    let pkcs12_file = dir.file("ca.p12");
+
+    let ca = CertificateAuthority::generate("/CN=ca.example.com", KeyType::EcdsaP256, 30).unwrap();
+    ca.save_der_files(&cert_file, &key_file).unwrap();
+    ca.save_pkcs12_file(&pkcs12_file, "third-wheel").unwrap();
+
+    let from_der = CertificateAuthority::load_from_der_files(&cert_file, &key_file).unwrap();
+    let from_pkcs12 =
+        CertificateAuthority::load_from_pkcs12_file(&pkcs12_file, "third-wheel").unwrap();
+
+    assert!(from_der.key.public_eq(&ca.key));
+    assert!(from_pkcs12.key.public_eq(&ca.key));
+    assert_eq!(
+        from_pkcs12.cert.to_der().unwrap(),
+        ca.cert.to_der().unwrap()
+    );
+}
@@ -0,0 +1,134 @@
+use hyper::{Body, Request};
+use third_wheel::*;
+
+use crate::harness::{forward, Harness, MyRequest, TestSite};
+
+/// The test site behind a proxy that treats the client's SNI as `client_sni`
+/// says, and mirrors upstreams that fail verification rather than refusing
+/// them
+fn set_up(client_sni: ClientSni) -> Harness {
+    let site = TestSite::start();
+    let proxy = site
+        .proxy(mitm_layer(forward))
+        .invalid_upstream_certificate(InvalidUpstreamCertificate::MirrorSelfSigned)
+        .client_sni(client_sni);
+    Harness::serve(site, proxy)
+}
+
+/// GET / from the test server through a tunnel to `authority`, but asking for
+/// `server_name` in the TLS handshake as a domain fronting client would.
+/// Returns an error if the client rejects the certificate the proxy presents.
+async fn fronted_get(
+    test_harness: &Harness,
+    authority: &str,
+    server_name: &str,
+) -> Result<String, String> {
+    let connector = native_tls::TlsConnector::builder()
+        .add_root_certificate(test_harness.third_wheel_root_certificate())
+        .build()
+        .unwrap();
+    fronted_get_with(test_harness, authority, server_name, connector).await
+}
+
+/// As `fronted_get`, but with the client's TLS settings in `connector`
+async fn fronted_get_with(
+    test_harness: &Harness,
+    authority: &str,
+    server_name: &str,
+    connector: native_tls::TlsConnector,
+) -> Result<String, String> {
+    let (domain, _) = test_harness.test_site_and_port.split_once(':').unwrap();
+    let stream = test_harness.tunnel(authority).await;
+
+    let stream = tokio_native_tls::TlsConnector::from(connector)
+        .connect(server_name, stream)
+        .await
+        .map_err(|e| e.to_string())?;
+    let (mut sender, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+    tokio::spawn(connection);
+    let response = sender
+        .send_request(
+            Request::get("/")
+                .header("host", domain)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    Ok(String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn certificate_is_forged_for_the_upstream_by_default() {
+    let test_harness = set_up(ClientSni::Ignore);
+    let authority = test_harness.test_site_and_port.clone();
+    assert!(fronted_get(&test_harness, &authority, "fronted.com")
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn certificate_is_forged_for_the_client_sni() {
+    let test_harness = set_up(ClientSni::ForgeCertificate);
+    let authority = test_harness.test_site_and_port.clone();
+    let response_body = fronted_get(&test_harness, &authority, "fronted.com")
+        .await
+        .unwrap();
+    let deserialized: MyRequest = serde_json::from_str(&response_body).unwrap();
+    assert_eq!(deserialized.method, "GET");
+    assert_eq!(deserialized.path, "/");
+}
+
+#[tokio::test]
+async fn client_sni_is_forwarded_upstream() {
+    // Only the server's address is in the CONNECT, which its certificate isn't
+    // for, so the client only gets a trusted certificate if the proxy
+    // reconnects with the name it asked for
+    let test_harness = set_up(ClientSni::ForgeCertificateAndForward);
+    let (domain, port) = test_harness.test_site_and_port.split_once(':').unwrap();
+    let authority = format!("127.0.0.1:{}", port);
+    let response_body = fronted_get(&test_harness, &authority, domain)
+        .await
+        .unwrap();
+    let deserialized: MyRequest = serde_json::from_str(&response_body).unwrap();
+    assert_eq!(deserialized.method, "GET");
+}
+
+#[tokio::test]
+async fn untrusted_upstream_stays_untrusted_without_forwarding() {
+    let test_harness = set_up(ClientSni::ForgeCertificate);
+    let (domain, port) = test_harness.test_site_and_port.split_once(':').unwrap();
+    let authority = format!("127.0.0.1:{}", port);
+    assert!(fronted_get(&test_harness, &authority, domain)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn untrusted_upstream_is_mirrored_when_forwarding() {
+    let site = TestSite::start();
+    let proxy = site
+        .proxy(mitm_layer(forward))
+        .additional_root_certificates(Vec::new())
+        .invalid_upstream_certificate(InvalidUpstreamCertificate::MirrorSelfSigned)
+        .client_sni(ClientSni::ForgeCertificateAndForward);
+    let test_harness = Harness::serve(site, proxy);
+    let (domain, port) = test_harness.test_site_and_port.split_once(':').unwrap();
+    let authority = format!("127.0.0.1:{}", port);
+    assert!(fronted_get(&test_harness, &authority, domain)
+        .await
+        .is_err());
+
+    // The reconnection made with the client's name fails verification too, so
+    // its certificate is mirrored rather than the tunnel being dropped
+    let connector = native_tls::TlsConnector::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    let response_body = fronted_get_with(&test_harness, &authority, domain, connector)
+        .await
+        .unwrap();
+    let deserialized: MyRequest = serde_json::from_str(&response_body).unwrap();
+    assert_eq!(deserialized.method, "GET");
+}
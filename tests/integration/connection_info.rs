@@ -0,0 +1,51 @@
+use http::{Request, Response};
+use hyper::Body;
+use third_wheel::*;
+
+use crate::harness::{proxied_client, spawn_proxy, Listener, TestCa};
+
+#[tokio::test]
+async fn connection_info_describes_the_tunnel() {
+    let ca = TestCa::generate();
+    let mitm = mitm_layer(|req: Request<Body>, _: ThirdWheel| {
+        let info = req.extensions().get::<ConnectionInfo>().unwrap();
+        let description = format!(
+            "{} {} {} {}",
+            info.id,
+            info.client_addr.ip(),
+            info.authority,
+            info.sni.as_deref().unwrap_or("none")
+        );
+        Box::pin(std::future::ready(Ok(Response::builder()
+            .body(Body::from(description))
+            .unwrap())))
+    });
+    let third_wheel_address = spawn_proxy(
+        MitmProxy::builder(mitm, ca.load())
+            .lazy_upstream_connection(true)
+            .upstream_resolver(StaticUpstream(
+                Upstream::new("offline.invalid", "443").with_sni("sni.invalid"),
+            )),
+        Listener::Http,
+    );
+
+    let client = proxied_client(third_wheel_address, &ca);
+    let mut descriptions = vec![];
+    for _ in 0..2 {
+        descriptions.push(
+            client
+                .get("https://sni.invalid/")
+                .send()
+                .await
+                .unwrap()
+                .text()
+                .await
+                .unwrap(),
+        );
+    }
+
+    let fields: Vec<&str> = descriptions[0].split(' ').collect();
+    assert_eq!(fields[1..], ["127.0.0.1", "sni.invalid:443", "sni.invalid"]);
+    // Both requests share a tunnel
+    assert_eq!(descriptions[0], descriptions[1]);
+}
@@ -1,20 +1,25 @@
-use futures::Future;
-use hyper::{Body, Request};
-use openssl::{pkey::PKey, rsa::Rsa};
+use futures::future::BoxFuture;
+use hyper::{Body, Request, Response};
+use openssl::asn1::Asn1Time;
+use openssl::bn::BigNum;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::x509::extension::ExtendedKeyUsage;
+use openssl::x509::{X509Name, X509};
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
-use run_script::{run_script, ScriptOptions};
 use serde::{Deserialize, Serialize};
 use simple_logger::SimpleLogger;
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{self, Write};
+use std::io;
 use std::iter;
 use std::net::SocketAddr;
 use std::sync::Once;
 use third_wheel::*;
-use tokio::sync::oneshot;
-use tower::Service;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tower::{Layer, Service};
 
 static INIT: Once = Once::new();
 
@@ -28,72 +33,115 @@ fn random_string() -> String {
     chars.to_lowercase()
 }
 
-pub struct TestCertificateLocations {
-    base_dir: String,
-    server_root_cert: String,
-    server_key: String,
-    third_wheel_root_cert: String,
-    third_wheel_key: String,
+/// A directory for a test's files, removed along with them when dropped
+pub struct TestDir {
+    path: String,
 }
 
-impl Drop for Harness {
-    fn drop(&mut self) {
-        std::fs::remove_dir_all(&self.root_certificates.base_dir).unwrap();
-        self.server_killer.take().unwrap().send(()).unwrap();
-        self.third_wheel_killer.take().unwrap().send(()).unwrap();
+impl TestDir {
+    pub fn new() -> Self {
+        let path = format!("/tmp/third_wheel_testing_{}", random_string());
+        std::fs::create_dir(&path).unwrap();
+        Self { path }
+    }
+
+    /// The path of the file called `name` in this directory
+    pub fn file(&self, name: &str) -> String {
+        format!("{}/{}", self.path, name)
     }
 }
 
-fn create_server_and_third_wheel_certificates() -> TestCertificateLocations {
-    let base_dir = format!("/tmp/third_wheel_testing_{}", random_string());
-    std::fs::create_dir(base_dir.clone()).unwrap();
-
-    let server_root_cert = format!("{}/{}", &base_dir, random_string());
-    let server_key = format!("{}/{}", &base_dir, random_string());
-    let third_wheel_root_cert = format!("{}/{}", &base_dir, random_string());
-    let third_wheel_key = format!("{}/{}", &base_dir, random_string());
-
-    let mut options = ScriptOptions::new();
-    options.working_directory = Some(base_dir.clone().into());
-    run_script!(
-        format!(
-            r#"
-            openssl req -x509 -newkey rsa:4096 -keyout {} -out {} -days 365 -passout pass:"third-wheel" -subj "/C=US/ST=private/L=province/O=city/CN=thirdwheel.com"
-            "#, &server_key, &server_root_cert),
-        &options
-    ).unwrap();
-    run_script!(
-        format!(
-            r#"
-            openssl req -x509 -newkey rsa:4096 -keyout {} -out {} -days 365 -passout pass:"third-wheel" -subj "/C=US/ST=private/L=province/O=city/CN=thirdwheel.com"
-            "#, &third_wheel_key, &third_wheel_root_cert),
-        &options
-    ).unwrap();
-
-    TestCertificateLocations {
-        base_dir,
-        server_root_cert,
-        server_key,
-        third_wheel_root_cert,
-        third_wheel_key,
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.path).unwrap();
     }
 }
 
-fn run_sign_certificate_for_domain(
-    outfile: &str,
-    cert_file: &str,
-    key_file: &str,
-    domain: &str,
-    passphrase: &str,
-) -> Result<(), Error> {
-    let ca = CertificateAuthority::load_from_pem_files_with_passphrase_on_key(
-        cert_file, key_file, passphrase,
-    )?;
-    let site_cert = create_signed_certificate_for_domain(domain, &ca)?;
-
-    let mut site_cert_file = File::create(outfile)?;
-    site_cert_file.write_all(&site_cert.to_pem()?)?;
-    Ok(())
+/// A certificate authority generated for a test, saved as PEM files in a
+/// directory of its own
+pub struct TestCa {
+    pub cert_file: String,
+    pub key_file: String,
+    dir: TestDir,
+}
+
+impl TestCa {
+    pub fn generate() -> Self {
+        let dir = TestDir::new();
+        let cert_file = dir.file("ca.pem");
+        let key_file = dir.file("ca.key");
+        CertificateAuthority::generate(
+            "/C=US/ST=private/L=province/O=city/CN=thirdwheel.com",
+            KeyType::Rsa2048,
+            365,
+        )
+        .unwrap()
+        .save_pem_files(&cert_file, &key_file)
+        .unwrap();
+        Self {
+            cert_file,
+            key_file,
+            dir,
+        }
+    }
+
+    pub fn load(&self) -> CertificateAuthority {
+        CertificateAuthority::load_from_pem_files(&self.cert_file, &self.key_file).unwrap()
+    }
+
+    pub fn root_certificate(&self) -> X509 {
+        X509::from_pem(&get_file_bytes(&self.cert_file)).unwrap()
+    }
+
+    pub fn reqwest_certificate(&self) -> reqwest::Certificate {
+        reqwest::Certificate::from_pem(&get_file_bytes(&self.cert_file)).unwrap()
+    }
+
+    pub fn native_tls_certificate(&self) -> native_tls::Certificate {
+        native_tls::Certificate::from_pem(&get_file_bytes(&self.cert_file)).unwrap()
+    }
+
+    /// A certificate for `domain` signed by this CA, saved as a PEM file, and
+    /// its key
+    fn sign_site(&self, domain: &str) -> (String, PKey<Private>) {
+        let site_key = KeyType::Rsa2048.generate().unwrap();
+        let site_cert =
+            create_signed_certificate_for_domain_with_key(domain, &site_key, &self.load()).unwrap();
+        let cert_file = self.dir.file(&format!("{}.pem", domain));
+        std::fs::write(&cert_file, site_cert.to_pem().unwrap()).unwrap();
+        (cert_file, site_key)
+    }
+
+    /// A client identity signed by this CA, as the proxy would load it
+    pub fn client_identity(&self) -> ClientIdentity {
+        let ca = self.load();
+        let key = KeyType::EcdsaP256.generate().unwrap();
+
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_text("CN", "client").unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(ca.cert.subject_name()).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.append_extension(ExtendedKeyUsage::new().client_auth().build().unwrap())
+            .unwrap();
+        cert.sign(&ca.key, MessageDigest::sha256()).unwrap();
+        let cert = cert.build();
+
+        let cert_file = self.dir.file("client.pem");
+        let key_file = self.dir.file("client.key");
+        std::fs::write(&cert_file, cert.to_pem().unwrap()).unwrap();
+        std::fs::write(&key_file, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        load_client_identity_from_pem_files(&cert_file, &key_file).unwrap()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
@@ -103,15 +151,26 @@ pub struct MyRequest<'a> {
     pub query_params: String,
     pub headers: HashMap<String, Vec<String>>,
     pub body: String,
+    /// The port the request came from, which is the same for requests sent
+    /// over the same connection
+    pub peer_port: u16,
 }
 
-fn get_warp_server(
-    server_key_location: &str,
-    server_cert_location: &str,
-) -> (SocketAddr, oneshot::Sender<()>, impl Future<Output = ()>) {
+/// Echoes every request back to the client as a `MyRequest`, and WebSocket
+/// messages sent to `/ws`
+fn echo_routes() -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    use futures::StreamExt;
     use warp::http::Response;
     use warp::Filter;
 
+    // Echoes every message back to the client
+    let websocket = warp::path("ws").and(warp::ws()).map(|ws: warp::ws::Ws| {
+        ws.on_upgrade(|socket| async {
+            let (sender, receiver) = socket.split();
+            receiver.forward(sender).await.ok();
+        })
+    });
+
     // Code stolen from: https://github.com/seanmonstar/warp/issues/139
     let routes = warp::any()
         .and(warp::method())
@@ -124,12 +183,14 @@ fn get_warp_server(
         )
         .and(warp::header::headers_cloned())
         .and(warp::body::bytes())
+        .and(warp::addr::remote())
         .map(
             |method: hyper::http::Method,
              path: warp::path::FullPath,
              query_params: String,
              headers: hyper::http::HeaderMap,
-             body: hyper::body::Bytes| {
+             body: hyper::body::Bytes,
+             peer: Option<SocketAddr>| {
                 let method = method.as_str();
                 let path = path.as_str();
                 let mut header_map = HashMap::new();
@@ -147,28 +208,77 @@ fn get_warp_server(
                     query_params,
                     headers: header_map,
                     body,
+                    peer_port: peer.map_or(0, |peer| peer.port()),
                 };
                 Response::builder().body(serde_json::to_string(&request).unwrap())
             },
         );
+    websocket.or(routes)
+}
 
-    let addr: SocketAddr = "127.0.0.1:0"
-        .parse()
-        .expect("Infallible: hardcoded socket address");
-    let (tx, rx) = oneshot::channel();
+/// A DNS over HTTPS server that answers every A query with 127.0.0.1 and
+/// every other query with nothing
+fn doh_route() -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    use warp::Filter;
+
+    warp::post()
+        .and(warp::path("dns-query"))
+        .and(warp::body::bytes())
+        .map(|query: hyper::body::Bytes| {
+            // The client sends a single question and nothing else
+            let question = &query[12..];
+            let is_a_query = question[question.len() - 4..question.len() - 2] == [0, 1];
+            let mut response = query[..12].to_vec();
+            response[2] |= 0x80; // QR: this is a response
+            response[6..8].copy_from_slice(&[0, u8::from(is_a_query)]);
+            response.extend_from_slice(question);
+            if is_a_query {
+                // A pointer to the question's name, then A, IN, a TTL and the address
+                response
+                    .extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 127, 0, 0, 1]);
+            }
+            warp::http::Response::builder()
+                .header("content-type", "application/dns-message")
+                .body(response)
+        })
+}
 
-    let key = get_file_bytes(server_key_location);
-    let key = PKey::from_rsa(
-        Rsa::private_key_from_pem_passphrase(&key, &"third-wheel".as_bytes()).unwrap(),
+/// A TLS server that speaks first as SMTP does: it greets every client and
+/// then echoes back whatever the client sends
+fn spawn_greeting_server(key: &PKey<Private>, cert_file: &str) -> SocketAddr {
+    let identity = native_tls::Identity::from_pkcs8(
+        &get_file_bytes(cert_file),
+        &key.private_key_to_pem_pkcs8().unwrap(),
     )
     .unwrap();
+    let acceptor =
+        tokio_native_tls::TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let address = listener.local_addr().unwrap();
 
-    let (server_address, server) = warp::serve(routes)
-        .tls()
-        .key(key.private_key_to_pem_pkcs8().unwrap())
-        .cert_path(server_cert_location)
-        .bind_with_graceful_shutdown(addr, async { rx.await.ok().unwrap() });
-    (server_address, tx, server)
+    tokio::spawn(async move {
+        let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let mut stream = match acceptor.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(_) => return,
+                };
+                stream.write_all(b"220 greetings\r\n").await.unwrap();
+                let mut buffer = [0; 1024];
+                loop {
+                    match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(length) => stream.write_all(&buffer[..length]).await.unwrap(),
+                    }
+                }
+            });
+        }
+    });
+    address
 }
 
 fn get_file_bytes(filename: &str) -> Vec<u8> {
@@ -178,98 +288,323 @@ fn get_file_bytes(filename: &str) -> Vec<u8> {
     cert
 }
 
+fn localhost() -> SocketAddr {
+    "127.0.0.1:0"
+        .parse()
+        .expect("Infallible: hardcoded socket address")
+}
+
+/// The site tests reach through the proxy: servers for a random .com domain
+/// with a certificate from a CA of their own, and the CA the proxy forges the
+/// site's certificates with. The servers run until the test's runtime stops.
+pub struct TestSite {
+    pub domain: String,
+    pub ca: TestCa,
+    pub proxy_ca: TestCa,
+    /// The port of the TLS server that answers for `domain`
+    pub port: u16,
+    /// The port of a plain HTTP echo server for `domain`
+    pub plain_port: u16,
+    cert_file: String,
+    key: PKey<Private>,
+}
+
+impl TestSite {
+    /// Start a TLS and a plain HTTP server that echo requests back as
+    /// `MyRequest`s
+    pub fn start() -> Self {
+        let mut site = Self::new();
+        let (address, server) = warp::serve(echo_routes())
+            .tls()
+            .key(site.key.private_key_to_pem_pkcs8().unwrap())
+            .cert_path(&site.cert_file)
+            .bind_ephemeral(localhost());
+        tokio::spawn(server);
+        site.port = address.port();
+        site
+    }
+
+    /// As `start`, but the TLS server only accepts clients with a certificate
+    /// from the site's CA
+    pub fn start_requiring_client_certificates() -> Self {
+        let mut site = Self::new();
+        let (address, server) = warp::serve(echo_routes())
+            .tls()
+            .key(site.key.private_key_to_pem_pkcs8().unwrap())
+            .cert_path(&site.cert_file)
+            .client_auth_required_path(&site.ca.cert_file)
+            .bind_ephemeral(localhost());
+        tokio::spawn(server);
+        site.port = address.port();
+        site
+    }
+
+    /// As `start`, but the TLS server doesn't speak HTTP: it greets its
+    /// clients and echoes what they send
+    pub fn start_greeting() -> Self {
+        let mut site = Self::new();
+        site.port = spawn_greeting_server(&site.key, &site.cert_file).port();
+        site
+    }
+
+    fn new() -> Self {
+        INIT.call_once(|| SimpleLogger::new().init().unwrap());
+        let ca = TestCa::generate();
+        let domain = format!("{}.com", random_string());
+        log::info!("Server domain name: {}", domain);
+        let (cert_file, key) = ca.sign_site(&domain);
+        let (plain_address, plain_server) = warp::serve(echo_routes()).bind_ephemeral(localhost());
+        tokio::spawn(plain_server);
+        Self {
+            domain,
+            ca,
+            proxy_ca: TestCa::generate(),
+            port: 0,
+            plain_port: plain_address.port(),
+            cert_file,
+            key,
+        }
+    }
+
+    /// A proxy that forges certificates with `proxy_ca`, finds the site by its
+    /// domain name and trusts the site's CA
+    pub fn proxy<T, U>(&self, mitm_layer: T) -> MitmProxyBuilder<T, U>
+    where
+        T: Layer<ThirdWheel, Service = U> + Sync + Send + 'static + Clone,
+        U: Service<Request<Body>, Response = Response<Body>> + Sync + Send + Clone + 'static,
+        U::Future: Send,
+        U::Error: std::error::Error + Send + Sync + 'static,
+    {
+        let mut host_mapping = HashMap::new();
+        host_mapping.insert(self.domain.clone(), "127.0.0.1".to_string());
+        MitmProxy::builder(mitm_layer, self.proxy_ca.load())
+            .additional_host_mappings(host_mapping)
+            .additional_root_certificates(vec![self.ca.root_certificate()])
+    }
+
+    pub fn upstream(&self) -> Upstream {
+        Upstream::new(&self.domain, &self.port.to_string())
+    }
+
+    /// The certificate the site's TLS server presents
+    pub fn certificate(&self) -> X509 {
+        X509::from_pem(&get_file_bytes(&self.cert_file)).unwrap()
+    }
+
+    /// A resolver that finds the site with DNS over HTTPS, asking a server of
+    /// its own with the site's certificate
+    pub fn doh_resolver(&self) -> DohResolver {
+        let (address, server) = warp::serve(doh_route())
+            .tls()
+            .key(self.key.private_key_to_pem_pkcs8().unwrap())
+            .cert_path(&self.cert_file)
+            .bind_ephemeral(localhost());
+        tokio::spawn(server);
+        DohResolver::new(&format!(
+            "https://{}:{}/dns-query",
+            self.domain,
+            address.port()
+        ))
+        .unwrap()
+        .bootstrap(&self.domain, address.ip())
+        .add_root_certificate(self.ca.root_certificate())
+    }
+
+    /// An identity from the site's CA for the proxy to present to the site
+    pub fn client_identities(&self) -> HashMap<String, ClientIdentity> {
+        let mut identities = HashMap::new();
+        identities.insert(self.domain.clone(), self.ca.client_identity());
+        identities
+    }
+}
+
+/// Sends every request on to the upstream unchanged, for `mitm_layer`
+pub fn forward(
+    request: Request<Body>,
+    mut third_wheel: ThirdWheel,
+) -> BoxFuture<'static, Result<Response<Body>, Error>> {
+    third_wheel.call(request)
+}
+
+/// Which of its listeners a proxy is started with
+#[derive(Clone, Copy)]
+pub enum Listener {
+    Http,
+    Socks5,
+    Transparent,
+}
+
+/// Start `proxy` on a port of its own, returning the address it listens on.
+/// It runs until the test's runtime stops.
+pub fn spawn_proxy<T, U>(proxy: MitmProxyBuilder<T, U>, listener: Listener) -> SocketAddr
+where
+    T: Layer<ThirdWheel, Service = U> + Sync + Send + 'static + Clone,
+    U: Service<Request<Body>, Response = Response<Body>> + Sync + Send + Clone + 'static,
+    U::Future: Send,
+    U::Error: std::error::Error + Send + Sync + 'static,
+{
+    let proxy = proxy.build();
+    match listener {
+        Listener::Http => {
+            let (address, proxy_fut) = proxy.bind(localhost());
+            tokio::spawn(proxy_fut);
+            address
+        }
+        Listener::Socks5 => {
+            let (address, proxy_fut) = proxy.bind_socks5(localhost());
+            tokio::spawn(proxy_fut);
+            address
+        }
+        Listener::Transparent => {
+            let (address, proxy_fut) = proxy.bind_transparent(localhost());
+            tokio::spawn(proxy_fut);
+            address
+        }
+    }
+}
+
+pub async fn set_up_for_trivial_mitm_test() -> Harness {
+    let site = TestSite::start();
+    let proxy = site.proxy(mitm_layer(forward));
+    Harness::serve(site, proxy)
+}
+
+/// A test site with a proxy in front of it, and clients to reach the site
+/// with and without the proxy
 pub struct Harness {
     pub test_site_and_port: String,
-    root_certificates: TestCertificateLocations,
-    server_killer: Option<oneshot::Sender<()>>,
-    third_wheel_killer: Option<oneshot::Sender<()>>,
+    /// The same server as `test_site_and_port`, without TLS
+    pub plain_site_and_port: String,
+    pub site: TestSite,
+    pub third_wheel_address: SocketAddr,
     pub client: reqwest::Client,
     pub non_proxied_client: reqwest::Client,
 }
 
-pub async fn set_up_for_trivial_mitm_test() -> Harness {
-    INIT.call_once(|| SimpleLogger::new().init().unwrap());
-    // set up certificates for third wheel and the test server
-    let root_certificates = create_server_and_third_wheel_certificates();
-    let server_cert_location = format!("{}/{}.pem", &root_certificates.base_dir, random_string());
-    log::info!("Server certificate stored at: {}", server_cert_location);
-    let test_domain_name = format!("{}.com", random_string());
-    log::info!("Server domain name: {}", test_domain_name);
-    run_sign_certificate_for_domain(
-        &server_cert_location,
-        &root_certificates.server_root_cert,
-        &root_certificates.server_key,
-        &test_domain_name,
-        "third-wheel",
-    )
-    .unwrap();
+impl Harness {
+    /// Start `proxy` in front of `site`, listening for HTTP proxy requests
+    pub fn serve<T, U>(site: TestSite, proxy: MitmProxyBuilder<T, U>) -> Self
+    where
+        T: Layer<ThirdWheel, Service = U> + Sync + Send + 'static + Clone,
+        U: Service<Request<Body>, Response = Response<Body>> + Sync + Send + Clone + 'static,
+        U::Future: Send,
+        U::Error: std::error::Error + Send + Sync + 'static,
+    {
+        Self::serve_on(site, proxy, Listener::Http)
+    }
+
+    /// Start `proxy` in front of `site` with `listener`. With a transparent
+    /// listener the harness's client connects to the proxy directly, as a
+    /// redirect would have it do, and `test_site_and_port` names the proxy's
+    /// port.
+    pub fn serve_on<T, U>(site: TestSite, proxy: MitmProxyBuilder<T, U>, listener: Listener) -> Self
+    where
+        T: Layer<ThirdWheel, Service = U> + Sync + Send + 'static + Clone,
+        U: Service<Request<Body>, Response = Response<Body>> + Sync + Send + Clone + 'static,
+        U::Future: Send,
+        U::Error: std::error::Error + Send + Sync + 'static,
+    {
+        log::info!("Initiating mitm proxy for domain {}", &site.domain);
+        let third_wheel_address = spawn_proxy(proxy, listener);
+        let (client, test_site_port) = match listener {
+            Listener::Transparent => (
+                redirected_client(&site.domain, third_wheel_address, &site.proxy_ca),
+                third_wheel_address.port(),
+            ),
+            Listener::Http | Listener::Socks5 => (
+                proxied_client(third_wheel_address, &site.proxy_ca),
+                site.port,
+            ),
+        };
+        let non_proxied_client = non_proxied_client(&site);
+
+        Self {
+            test_site_and_port: format!("{}:{}", site.domain, test_site_port),
+            plain_site_and_port: format!("{}:{}", site.domain, site.plain_port),
+            site,
+            third_wheel_address,
+            client,
+            non_proxied_client,
+        }
+    }
 
-    // Set up target echo server
-    let (server_addr, server_killer, server) =
-        get_warp_server(&root_certificates.server_key, &server_cert_location);
+    /// A client that trusts the test server's CA rather than the proxy's
+    pub fn server_trusting_proxied_client(&self) -> reqwest::Client {
+        proxied_client(self.third_wheel_address, &self.site.ca)
+    }
 
-    // Create a DNS override to the local server
-    let mut host_mapping = HashMap::new();
-    host_mapping.insert(test_domain_name.clone(), "127.0.0.1".to_string());
+    /// A client that accepts any certificate the proxy shows it
+    pub fn insecure_proxied_client(&self) -> reqwest::Client {
+        reqwest::Client::builder()
+            .proxy(reqwest::Proxy::https(format!("http://{}", self.third_wheel_address)).unwrap())
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap()
+    }
 
-    // Generate a server certificate
-    let server_root_cert =
-        native_tls::Certificate::from_pem(&get_file_bytes(&root_certificates.server_root_cert))
+    /// A connection through the proxy to `authority`, opened with CONNECT
+    pub async fn tunnel(&self, authority: &str) -> TcpStream {
+        let mut stream = TcpStream::connect(self.third_wheel_address).await.unwrap();
+        stream
+            .write_all(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", authority).as_bytes())
+            .await
             .unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            let mut byte = [0; 1];
+            stream.read_exact(&mut byte).await.unwrap();
+            response.push(byte[0]);
+        }
+        assert!(response.starts_with(b"HTTP/1.1 200"));
+        stream
+    }
 
-    let third_wheel_ca = CertificateAuthority::load_from_pem_files_with_passphrase_on_key(
-        &root_certificates.third_wheel_root_cert,
-        &root_certificates.third_wheel_key,
-        "third-wheel",
-    )
-    .unwrap();
+    /// A client that authenticates to the proxy as `username`
+    pub fn authenticating_proxied_client(&self, username: &str, password: &str) -> reqwest::Client {
+        reqwest::Client::builder()
+            .proxy(
+                reqwest::Proxy::https(format!("http://{}", self.third_wheel_address))
+                    .unwrap()
+                    .basic_auth(username, password),
+            )
+            .add_root_certificate(self.site.proxy_ca.reqwest_certificate())
+            .build()
+            .unwrap()
+    }
 
-    // Set up a trivial pass-through mitm proxy
-    let trivial_mitm = MitmProxy::builder(
-        mitm_layer(|req: Request<Body>, mut third_wheel: ThirdWheel| third_wheel.call(req)),
-        third_wheel_ca,
-    )
-    .additional_root_certificates(vec![server_root_cert])
-    .additional_host_mappings(host_mapping)
-    .build();
-
-    let (third_wheel_killer, receiver) = tokio::sync::oneshot::channel();
-    let (third_wheel_address, mitm_fut) = trivial_mitm
-        .bind_with_graceful_shutdown("127.0.0.1:0".parse().unwrap(), async {
-            receiver.await.ok().unwrap()
-        });
-    log::info!("Initiating server for domain {}", &test_domain_name);
-    tokio::spawn(server);
-    log::info!("Initiating mitm proxy for domain {}", &test_domain_name);
-    tokio::spawn(mitm_fut);
-
-    let client = proxied_client(
-        third_wheel_address,
-        &root_certificates.third_wheel_root_cert,
-    );
-
-    let non_proxied_client = non_proxied_client(
-        &test_domain_name,
-        server_addr,
-        &root_certificates.server_root_cert,
-    );
-
-    Harness {
-        test_site_and_port: format!("{}:{}", test_domain_name, server_addr.port()),
-        client,
-        non_proxied_client,
-        root_certificates,
-        server_killer: Some(server_killer),
-        third_wheel_killer: Some(third_wheel_killer),
+    pub fn third_wheel_root_certificate(&self) -> native_tls::Certificate {
+        self.site.proxy_ca.native_tls_certificate()
+    }
+
+    /// A client that sends plain HTTP requests to the proxy in absolute form
+    pub fn plain_proxied_client(&self) -> reqwest::Client {
+        reqwest::Client::builder()
+            .proxy(reqwest::Proxy::http(format!("http://{}", self.third_wheel_address)).unwrap())
+            .build()
+            .unwrap()
+    }
+
+    /// A client of its own, so its requests go through a new tunnel
+    pub fn new_proxied_client(&self) -> reqwest::Client {
+        proxied_client(self.third_wheel_address, &self.site.proxy_ca)
     }
 }
 
-fn proxied_client(
+/// A client whose connections to `domain` go to the transparent proxy at
+/// `third_wheel_addr` instead
+pub fn redirected_client(
+    domain: &str,
     third_wheel_addr: SocketAddr,
-    third_wheel_cert_location: &str,
+    third_wheel_ca: &TestCa,
 ) -> reqwest::Client {
-    let third_wheel_cert =
-        reqwest::Certificate::from_pem(&get_file_bytes(third_wheel_cert_location)).unwrap();
+    reqwest::Client::builder()
+        .resolve(domain, third_wheel_addr)
+        .add_root_certificate(third_wheel_ca.reqwest_certificate())
+        .build()
+        .unwrap()
+}
+
+pub fn proxied_client(third_wheel_addr: SocketAddr, third_wheel_ca: &TestCa) -> reqwest::Client {
     reqwest::Client::builder()
         .proxy(
             reqwest::Proxy::https(format!(
@@ -279,21 +614,15 @@ fn proxied_client(
             ))
             .unwrap(),
         )
-        .add_root_certificate(third_wheel_cert)
+        .add_root_certificate(third_wheel_ca.reqwest_certificate())
         .build()
         .unwrap()
 }
 
-fn non_proxied_client(
-    test_domain: &str,
-    server_addr: SocketAddr,
-    server_cert_location: &str,
-) -> reqwest::Client {
-    let server_cert =
-        reqwest::Certificate::from_pem(&get_file_bytes(server_cert_location)).unwrap();
+fn non_proxied_client(site: &TestSite) -> reqwest::Client {
     reqwest::Client::builder()
-        .resolve(test_domain, server_addr)
-        .add_root_certificate(server_cert)
+        .resolve(&site.domain, ([127, 0, 0, 1], site.port).into())
+        .add_root_certificate(site.ca.reqwest_certificate())
         .build()
         .unwrap()
 }
@@ -0,0 +1,57 @@
+use third_wheel::{mitm_layer, HostPatterns};
+
+use crate::harness::{forward, Harness, MyRequest, TestSite};
+
+/// The test site behind a proxy that only intercepts the tunnels `filter`
+/// picks
+fn set_up(filter: HostPatterns) -> Harness {
+    let site = TestSite::start();
+    let proxy = site.proxy(mitm_layer(forward)).intercept_filter(filter);
+    Harness::serve(site, proxy)
+}
+
+async fn get_with_client(
+    test_harness: &Harness,
+    client: &reqwest::Client,
+) -> Result<String, reqwest::Error> {
+    client
+        .get(format!("https://{}/", test_harness.test_site_and_port))
+        .send()
+        .await?
+        .text()
+        .await
+}
+
+#[tokio::test]
+async fn excluded_host_is_spliced_through_to_the_server() {
+    // The test server's random domain is always a .com
+    let test_harness = set_up(HostPatterns::except(&["*.COM"]));
+
+    // The client sees the server's own certificate rather than a forged one
+    let response_body = get_with_client(
+        &test_harness,
+        &test_harness.server_trusting_proxied_client(),
+    )
+    .await
+    .unwrap();
+    let deserialized: MyRequest = serde_json::from_str(&response_body).unwrap();
+    assert_eq!(deserialized.method, "GET");
+    assert!(get_with_client(&test_harness, &test_harness.client)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn included_host_is_intercepted() {
+    let test_harness = set_up(HostPatterns::only(&["*.com"]));
+
+    assert!(get_with_client(&test_harness, &test_harness.client)
+        .await
+        .is_ok());
+    assert!(get_with_client(
+        &test_harness,
+        &test_harness.server_trusting_proxied_client()
+    )
+    .await
+    .is_err());
+}
@@ -0,0 +1,30 @@
+use http::{Request, Response};
+use hyper::Body;
+use third_wheel::*;
+
+use crate::harness::{proxied_client, spawn_proxy, Listener, TestCa};
+
+#[tokio::test]
+async fn lazy_tunnel_answered_by_mitm_never_needs_upstream() {
+    let ca = TestCa::generate();
+    let mitm = mitm_layer(|_: Request<Body>, _: ThirdWheel| {
+        Box::pin(std::future::ready(Ok(Response::builder()
+            .body(Body::from("mocked"))
+            .unwrap())))
+    });
+    let third_wheel_address = spawn_proxy(
+        MitmProxy::builder(mitm, ca.load()).lazy_upstream_connection(true),
+        Listener::Http,
+    );
+
+    // Nothing resolves under .invalid, so this only works if the proxy never dials it
+    let response_body = proxied_client(third_wheel_address, &ca)
+        .get("https://offline.invalid/")
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert_eq!(response_body, "mocked");
+}
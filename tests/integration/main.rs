@@ -1,3 +1,19 @@
+mod certificate_authority;
+mod client_sni;
+mod connection_info;
 mod harness;
+mod intercept_filter;
+mod lazy_connection;
+mod mutual_tls;
+mod plain_http;
+mod proxy_auth;
 mod proxy_vs_nonproxy;
+mod rerouting;
+mod resolver;
 mod simple_proxying;
+mod socks5;
+mod stream_interceptor;
+mod transparent_proxy;
+mod upstream_proxy;
+mod upstream_tls_policy;
+mod websocket;
@@ -0,0 +1,36 @@
+use third_wheel::mitm_layer;
+
+use crate::harness::{forward, Harness, MyRequest, TestSite};
+
+#[tokio::test]
+async fn proxy_presents_client_certificate_to_upstream() {
+    let site = TestSite::start_requiring_client_certificates();
+    let proxy = site
+        .proxy(mitm_layer(forward))
+        .upstream_client_identities(site.client_identities());
+    let test_harness = Harness::serve(site, proxy);
+    let response_body = test_harness
+        .client
+        .get(format!("https://{}/", test_harness.test_site_and_port))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    let deserialized: MyRequest = serde_json::from_str(&response_body).unwrap();
+    assert_eq!(deserialized.method, "GET");
+}
+
+#[tokio::test]
+async fn upstream_refuses_proxy_without_client_certificate() {
+    let site = TestSite::start_requiring_client_certificates();
+    let proxy = site.proxy(mitm_layer(forward));
+    let test_harness = Harness::serve(site, proxy);
+    assert!(test_harness
+        .client
+        .get(format!("https://{}/", test_harness.test_site_and_port))
+        .send()
+        .await
+        .is_err());
+}
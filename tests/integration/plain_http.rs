@@ -0,0 +1,72 @@
+use crate::harness::{set_up_for_trivial_mitm_test, MyRequest};
+
+#[tokio::test]
+async fn plain_get_request_is_forwarded() {
+    let test_harness = set_up_for_trivial_mitm_test().await;
+    let response_body = test_harness
+        .plain_proxied_client()
+        .get(format!(
+            "http://{}/plain?a=b",
+            test_harness.plain_site_and_port
+        ))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+
+    let deserialized: MyRequest = serde_json::from_str(&response_body).unwrap();
+    assert_eq!(deserialized.method, "GET");
+    assert_eq!(deserialized.path, "/plain");
+    assert_eq!(deserialized.query_params, "a=b");
+}
+
+#[tokio::test]
+async fn plain_post_body_is_forwarded() {
+    let test_harness = set_up_for_trivial_mitm_test().await;
+    let body = "this is a body".repeat(10_000);
+    let response_body = test_harness
+        .plain_proxied_client()
+        .post(format!("http://{}/", test_harness.plain_site_and_port))
+        .body(body.clone())
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+
+    let deserialized: MyRequest = serde_json::from_str(&response_body).unwrap();
+    assert_eq!(deserialized.method, "POST");
+    assert_eq!(deserialized.body, body);
+}
+
+#[tokio::test]
+async fn concurrent_plain_requests_are_all_answered() {
+    let test_harness = set_up_for_trivial_mitm_test().await;
+    let client = test_harness.plain_proxied_client();
+    for round in 0..3 {
+        // Large bodies keep the pooled connections busy while the next
+        // requests check them out
+        let requests = (0..10).map(|i| {
+            client
+                .post(format!(
+                    "http://{}/pooled?request={}",
+                    test_harness.plain_site_and_port, i
+                ))
+                .body(format!("{}", round).repeat(100_000))
+                .send()
+        });
+        for (i, response) in futures::future::join_all(requests)
+            .await
+            .into_iter()
+            .enumerate()
+        {
+            let response_body = response.unwrap().text().await.unwrap();
+            let deserialized: MyRequest = serde_json::from_str(&response_body).unwrap();
+            assert_eq!(deserialized.query_params, format!("request={}", i));
+            assert_eq!(deserialized.body, format!("{}", round).repeat(100_000));
+        }
+    }
+}
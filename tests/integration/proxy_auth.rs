@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+
+use hyper::{Body, Request};
+use third_wheel::*;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tower::Service;
+
+use crate::harness::{Harness, MyRequest, TestSite};
+
+/// The test site behind a proxy that only lets alice in, and tells the client
+/// who the proxy took it for
+fn set_up() -> Harness {
+    let mut credentials = HashMap::new();
+    credentials.insert("alice".to_string(), "wonderland".to_string());
+    let site = TestSite::start();
+    let proxy = site
+        .proxy(mitm_layer(
+            |req: Request<Body>, mut third_wheel: ThirdWheel| {
+                let principal = req
+                    .extensions()
+                    .get::<ConnectionInfo>()
+                    .and_then(|info| info.principal.clone());
+                let response = third_wheel.call(req);
+                Box::pin(async move {
+                    let mut response = response.await?;
+                    if let Some(principal) = principal {
+                        response
+                            .headers_mut()
+                            .insert("x-proxy-principal", principal.parse().unwrap());
+                    }
+                    Ok(response)
+                })
+            },
+        ))
+        .proxy_authenticator(credentials);
+    Harness::serve(site, proxy)
+}
+
+#[tokio::test]
+async fn connect_without_credentials_is_challenged() {
+    let test_harness = set_up();
+    let mut stream = TcpStream::connect(test_harness.third_wheel_address)
+        .await
+        .unwrap();
+    stream
+        .write_all(
+            format!(
+                "CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n",
+                test_harness.test_site_and_port
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        response.push(stream.read_u8().await.unwrap());
+    }
+    let response = String::from_utf8(response).unwrap().to_ascii_lowercase();
+    assert!(response.starts_with("http/1.1 407"));
+    assert!(response.contains("\r\nproxy-authenticate: basic realm=\"third-wheel\"\r\n"));
+}
+
+#[tokio::test]
+async fn wrong_password_is_refused() {
+    let test_harness = set_up();
+    let client = test_harness.authenticating_proxied_client("alice", "looking-glass");
+    assert!(client
+        .get(format!("https://{}/", test_harness.test_site_and_port))
+        .send()
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn principal_is_passed_to_the_mitm_layer() {
+    let test_harness = set_up();
+    let client = test_harness.authenticating_proxied_client("alice", "wonderland");
+    let response = client
+        .get(format!("https://{}/", test_harness.test_site_and_port))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["x-proxy-principal"], "alice");
+    let response_body = response.text().await.unwrap();
+    let deserialized: MyRequest = serde_json::from_str(&response_body).unwrap();
+    assert_eq!(deserialized.method, "GET");
+}
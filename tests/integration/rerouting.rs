@@ -0,0 +1,81 @@
+use std::time::Duration;
+
+use hyper::{Body, Request};
+use third_wheel::*;
+
+use crate::harness::{Harness, MyRequest, TestSite};
+
+#[tokio::test]
+async fn request_is_sent_to_the_upstream_chosen_by_call_to() {
+    let site = TestSite::start();
+    let upstream = site.upstream();
+    let proxy = site
+        .proxy(mitm_layer(
+            move |req: Request<Body>, third_wheel: ThirdWheel| {
+                third_wheel.call_to(upstream.clone(), req)
+            },
+        ))
+        .lazy_upstream_connection(true);
+    let test_harness = Harness::serve(site, proxy);
+    // Nothing resolves under .invalid, so only the rerouted request can succeed
+    let response_body = test_harness
+        .client
+        .get("https://elsewhere.invalid/rerouted?a=b")
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+
+    let deserialized: MyRequest = serde_json::from_str(&response_body).unwrap();
+    assert_eq!(deserialized.path, "/rerouted");
+    assert_eq!(deserialized.query_params, "a=b");
+}
+
+#[tokio::test]
+async fn connection_stays_out_of_the_pool_while_a_response_body_streams() {
+    let site = TestSite::start();
+    let upstream = Upstream::new(&site.domain, &site.plain_port.to_string());
+    let proxy = site
+        .proxy(mitm_layer(
+            move |mut req: Request<Body>, third_wheel: ThirdWheel| {
+                // Plain HTTP/1.1, so a connection answers one request at a time
+                *req.uri_mut() = format!("http://elsewhere.invalid{}", req.uri().path())
+                    .parse()
+                    .unwrap();
+                third_wheel.call_to(upstream.clone(), req)
+            },
+        ))
+        .lazy_upstream_connection(true);
+    let test_harness = Harness::serve(site, proxy);
+    // Far more than the connections on the way buffer, so the first response
+    // is still streaming when the second request is sent
+    let body = "a".repeat(4 * 1024 * 1024);
+
+    let first = test_harness
+        .client
+        .post("https://elsewhere.invalid/first")
+        .body(body.clone())
+        .send()
+        .await
+        .unwrap();
+    let second = tokio::time::timeout(
+        Duration::from_secs(10),
+        test_harness
+            .client
+            .post("https://elsewhere.invalid/second")
+            .body(body.clone())
+            .send(),
+    )
+    .await
+    .expect("the second request waited for the first response's body")
+    .unwrap();
+
+    for (response, path) in [(first, "/first"), (second, "/second")] {
+        let response_body = response.text().await.unwrap();
+        let deserialized: MyRequest = serde_json::from_str(&response_body).unwrap();
+        assert_eq!(deserialized.path, path);
+        assert_eq!(deserialized.body, body);
+    }
+}
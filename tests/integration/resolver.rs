@@ -0,0 +1,26 @@
+use third_wheel::mitm_layer;
+
+use crate::harness::{forward, Harness, MyRequest, TestSite};
+
+#[tokio::test]
+async fn upstream_is_found_with_dns_over_https() {
+    let site = TestSite::start();
+    // In place of the host mapping to the site
+    let proxy = site
+        .proxy(mitm_layer(forward))
+        .resolver(site.doh_resolver());
+    let test_harness = Harness::serve(site, proxy);
+    let response_body = test_harness
+        .client
+        .get(format!("https://{}/", test_harness.test_site_and_port))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+
+    let deserialized: MyRequest = serde_json::from_str(&response_body).unwrap();
+    assert_eq!(deserialized.method, "GET");
+    assert_eq!(deserialized.path, "/");
+}
@@ -69,3 +69,70 @@ async fn post_body_correctly_sent() {
     assert_eq!(deserialized.query_params, "");
     assert_eq!(deserialized.body, body);
 }
+
+#[tokio::test]
+async fn http2_negotiated_on_both_legs() {
+    let test_harness = set_up_for_trivial_mitm_test().await;
+    let response = test_harness
+        .client
+        .get(format!("https:/{}/", test_harness.test_site_and_port))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.version(), reqwest::Version::HTTP_2);
+    let response_body = response.text().await.unwrap();
+    let deserialized: MyRequest = serde_json::from_str(&response_body).unwrap();
+    assert_eq!(deserialized.method, "GET");
+    assert_eq!(deserialized.path, "/");
+}
+
+#[tokio::test]
+async fn concurrent_requests_are_all_answered() {
+    let test_harness = set_up_for_trivial_mitm_test().await;
+    let requests = (0..50).map(|i| {
+        test_harness
+            .client
+            .get(format!(
+                "https:/{}/concurrent?request={}",
+                test_harness.test_site_and_port, i
+            ))
+            .send()
+    });
+
+    for (i, response) in futures::future::join_all(requests)
+        .await
+        .into_iter()
+        .enumerate()
+    {
+        let response_body = response.unwrap().text().await.unwrap();
+        let deserialized: MyRequest = serde_json::from_str(&response_body).unwrap();
+        assert_eq!(deserialized.query_params, format!("request={}", i));
+    }
+}
+
+#[tokio::test]
+async fn successive_tunnels_reuse_upstream_connection() {
+    let test_harness = set_up_for_trivial_mitm_test().await;
+    let mut peer_ports = Vec::new();
+    for i in 0..3 {
+        let response_body = test_harness
+            .new_proxied_client()
+            .get(format!(
+                "https://{}/tunnel?number={}",
+                test_harness.test_site_and_port, i
+            ))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        let deserialized: MyRequest = serde_json::from_str(&response_body).unwrap();
+        assert_eq!(deserialized.query_params, format!("number={}", i));
+        peer_ports.push(deserialized.peer_port);
+    }
+    // Every tunnel's requests reached the server over the same connection
+    assert_ne!(peer_ports[0], 0);
+    assert!(peer_ports.iter().all(|port| *port == peer_ports[0]));
+}
@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+
+use hyper::{Body, Request};
+use third_wheel::*;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::harness::{forward, Harness, Listener, MyRequest, TestSite};
+
+/// GET / from the test server through the proxy's SOCKS5 listener, asking for
+/// the server by name as `curl --socks5-hostname` does. Returns the SOCKS5
+/// reply code if the proxy refuses the request.
+async fn socks5_get(
+    test_harness: &Harness,
+    credentials: Option<(&str, &str)>,
+) -> Result<String, u8> {
+    let (domain, port) = test_harness.test_site_and_port.split_once(':').unwrap();
+    let mut stream = TcpStream::connect(test_harness.third_wheel_address)
+        .await
+        .unwrap();
+
+    let method = if credentials.is_some() { 2 } else { 0 };
+    stream.write_all(&[5, 1, method]).await.unwrap();
+    let mut chosen = [0; 2];
+    stream.read_exact(&mut chosen).await.unwrap();
+    if chosen[1] != method {
+        return Err(chosen[1]);
+    }
+    if let Some((username, password)) = credentials {
+        let mut message = vec![1, username.len() as u8];
+        message.extend_from_slice(username.as_bytes());
+        message.push(password.len() as u8);
+        message.extend_from_slice(password.as_bytes());
+        stream.write_all(&message).await.unwrap();
+        let mut status = [0; 2];
+        stream.read_exact(&mut status).await.unwrap();
+        if status[1] != 0 {
+            return Err(status[1]);
+        }
+    }
+
+    let mut request = vec![5, 1, 0, 3, domain.len() as u8];
+    request.extend_from_slice(domain.as_bytes());
+    request.extend_from_slice(&port.parse::<u16>().unwrap().to_be_bytes());
+    stream.write_all(&request).await.unwrap();
+    let mut reply = [0; 10];
+    stream.read_exact(&mut reply).await.unwrap();
+    if reply[1] != 0 {
+        return Err(reply[1]);
+    }
+
+    let connector = native_tls::TlsConnector::builder()
+        .add_root_certificate(test_harness.third_wheel_root_certificate())
+        .build()
+        .unwrap();
+    let stream = tokio_native_tls::TlsConnector::from(connector)
+        .connect(domain, stream)
+        .await
+        .unwrap();
+    let (mut sender, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+    tokio::spawn(connection);
+    let response = sender
+        .send_request(
+            Request::get("/")
+                .header("host", domain)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    Ok(String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn socks5_connect_is_intercepted() {
+    let site = TestSite::start();
+    let proxy = site.proxy(mitm_layer(forward));
+    let test_harness = Harness::serve_on(site, proxy, Listener::Socks5);
+    let response_body = socks5_get(&test_harness, None).await.unwrap();
+    let deserialized: MyRequest = serde_json::from_str(&response_body).unwrap();
+    assert_eq!(deserialized.method, "GET");
+    assert_eq!(deserialized.path, "/");
+}
+
+#[tokio::test]
+async fn socks5_clients_must_give_valid_credentials() {
+    let mut credentials = HashMap::new();
+    credentials.insert("alice".to_string(), "secret".to_string());
+    let site = TestSite::start();
+    let proxy = site
+        .proxy(mitm_layer(forward))
+        .socks5_credentials(credentials);
+    let test_harness = Harness::serve_on(site, proxy, Listener::Socks5);
+
+    assert!(socks5_get(&test_harness, Some(("alice", "secret")))
+        .await
+        .is_ok());
+    // Refused at the username/password stage
+    assert_eq!(
+        socks5_get(&test_harness, Some(("alice", "wrong"))).await,
+        Err(1)
+    );
+    // No acceptable methods
+    assert_eq!(socks5_get(&test_harness, None).await, Err(0xff));
+}
+
+#[tokio::test]
+async fn socks5_clients_are_checked_by_the_proxy_authenticator() {
+    let mut credentials = HashMap::new();
+    credentials.insert("alice".to_string(), "secret".to_string());
+    let site = TestSite::start();
+    let proxy = site
+        .proxy(mitm_layer(forward))
+        .proxy_authenticator(credentials);
+    let test_harness = Harness::serve_on(site, proxy, Listener::Socks5);
+
+    assert!(socks5_get(&test_harness, Some(("alice", "secret")))
+        .await
+        .is_ok());
+    assert_eq!(
+        socks5_get(&test_harness, Some(("alice", "secre7"))).await,
+        Err(1)
+    );
+    assert_eq!(socks5_get(&test_harness, None).await, Err(0xff));
+}
@@ -0,0 +1,145 @@
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use hyper::{Body, Request};
+use third_wheel::*;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio_native_tls::TlsStream;
+
+use crate::harness::{forward, Harness, MyRequest, TestSite};
+
+/// A decrypted tunnel through the proxy to the test server
+async fn tls_tunnel(test_harness: &Harness) -> TlsStream<TcpStream> {
+    let (domain, _) = test_harness.test_site_and_port.split_once(':').unwrap();
+    let stream = test_harness.tunnel(&test_harness.test_site_and_port).await;
+    let connector = native_tls::TlsConnector::builder()
+        .add_root_certificate(test_harness.third_wheel_root_certificate())
+        .build()
+        .unwrap();
+    tokio_native_tls::TlsConnector::from(connector)
+        .connect(domain, stream)
+        .await
+        .unwrap()
+}
+
+/// Talk to the greeting server through the proxy, returning every line the
+/// client reads once it has said `hello`
+async fn greet(test_harness: &Harness, lines: usize) -> Vec<String> {
+    let mut stream = BufReader::new(tls_tunnel(test_harness).await);
+    stream.write_all(b"hello\r\n").await.unwrap();
+
+    let mut read = Vec::new();
+    for _ in 0..lines {
+        let mut line = String::new();
+        stream.read_line(&mut line).await.unwrap();
+        read.push(line);
+    }
+    read
+}
+
+#[tokio::test]
+async fn non_http_tunnel_is_spliced_by_default() {
+    let site = TestSite::start_greeting();
+    let proxy = site.proxy(mitm_layer(forward));
+    let test_harness = Harness::serve(site, proxy);
+    assert_eq!(
+        greet(&test_harness, 2).await,
+        vec!["220 greetings\r\n", "hello\r\n"]
+    );
+}
+
+/// Announces itself to the client, then splices the tunnel
+fn announce(
+    mut client: Box<dyn DecryptedStream>,
+    mut upstream: Box<dyn DecryptedStream>,
+    info: ConnectionInfo,
+) -> BoxFuture<'static, Result<(), Error>> {
+    Box::pin(async move {
+        let announcement = format!("intercepted {}\r\n", info.authority);
+        client.write_all(announcement.as_bytes()).await?;
+        tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+        Ok(())
+    })
+}
+
+#[tokio::test]
+async fn non_http_tunnel_goes_to_stream_interceptor() {
+    let site = TestSite::start_greeting();
+    let proxy = site.proxy(mitm_layer(forward)).stream_interceptor(announce);
+    let test_harness = Harness::serve(site, proxy);
+    assert_eq!(
+        greet(&test_harness, 3).await,
+        vec![
+            format!("intercepted {}\r\n", test_harness.test_site_and_port),
+            "220 greetings\r\n".to_string(),
+            "hello\r\n".to_string(),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn client_waiting_for_the_server_goes_to_stream_interceptor_after_timeout() {
+    let site = TestSite::start_greeting();
+    let proxy = site
+        .proxy(mitm_layer(forward))
+        .stream_interceptor(announce)
+        .server_speaks_first_timeout(Some(Duration::from_millis(200)));
+    let test_harness = Harness::serve(site, proxy);
+    let mut stream = BufReader::new(tls_tunnel(&test_harness).await);
+    let mut read = Vec::new();
+    for _ in 0..2 {
+        let mut line = String::new();
+        stream.read_line(&mut line).await.unwrap();
+        read.push(line);
+    }
+    assert_eq!(
+        read,
+        vec![
+            format!("intercepted {}\r\n", test_harness.test_site_and_port),
+            "220 greetings\r\n".to_string(),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn client_waiting_for_the_server_is_spliced_by_default() {
+    let site = TestSite::start_greeting();
+    let proxy = site.proxy(mitm_layer(forward));
+    let test_harness = Harness::serve(site, proxy);
+    let mut stream = BufReader::new(tls_tunnel(&test_harness).await);
+    let mut greeting = String::new();
+    tokio::time::timeout(Duration::from_secs(10), stream.read_line(&mut greeting))
+        .await
+        .expect("the proxy waited for the client to speak first")
+        .unwrap();
+    assert_eq!(greeting, "220 greetings\r\n");
+}
+
+#[tokio::test]
+async fn slow_http_client_is_still_served_as_http_without_a_timeout() {
+    let site = TestSite::start();
+    let proxy = site
+        .proxy(mitm_layer(forward))
+        .stream_interceptor(announce)
+        .server_speaks_first_timeout(None);
+    let test_harness = Harness::serve(site, proxy);
+    let (domain, _) = test_harness.test_site_and_port.split_once(':').unwrap();
+    let stream = tls_tunnel(&test_harness).await;
+    // Longer than the default timeout
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    let (mut sender, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+    tokio::spawn(connection);
+    let response = sender
+        .send_request(
+            Request::get("/slow")
+                .header("host", domain)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let deserialized: MyRequest = serde_json::from_slice(&body).unwrap();
+    assert_eq!(deserialized.path, "/slow");
+}
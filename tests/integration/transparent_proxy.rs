@@ -0,0 +1,52 @@
+use third_wheel::*;
+
+use crate::harness::{forward, redirected_client, Harness, Listener, MyRequest, TestSite};
+
+#[tokio::test]
+async fn redirected_connection_is_intercepted_by_server_name() {
+    let site = TestSite::start();
+    // Without a redirect the proxy can't tell which port the client wanted
+    let proxy = site
+        .proxy(mitm_layer(forward))
+        .upstream_resolver(StaticUpstream(site.upstream()));
+    let test_harness = Harness::serve_on(site, proxy, Listener::Transparent);
+    let response_body = test_harness
+        .client
+        .post(format!(
+            "https://{}/submit",
+            test_harness.test_site_and_port
+        ))
+        .body("transparent")
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+
+    let deserialized: MyRequest = serde_json::from_str(&response_body).unwrap();
+    assert_eq!(deserialized.method, "POST");
+    assert_eq!(deserialized.path, "/submit");
+    assert_eq!(deserialized.body, "transparent");
+}
+
+#[tokio::test]
+async fn ipv6_connection_is_intercepted_by_server_name() {
+    let site = TestSite::start();
+    let proxy = site
+        .proxy(mitm_layer(forward))
+        .upstream_resolver(StaticUpstream(site.upstream()));
+    let (address, proxy_fut) = proxy.build().bind_transparent("[::1]:0".parse().unwrap());
+    tokio::spawn(proxy_fut);
+    let response_body = redirected_client(&site.domain, address, &site.proxy_ca)
+        .get(format!("https://{}:{}/ipv6", site.domain, address.port()))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+
+    let deserialized: MyRequest = serde_json::from_str(&response_body).unwrap();
+    assert_eq!(deserialized.path, "/ipv6");
+}
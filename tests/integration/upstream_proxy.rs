@@ -0,0 +1,51 @@
+use third_wheel::*;
+
+use crate::harness::{forward, spawn_proxy, Harness, Listener, MyRequest, TestSite};
+
+/// The test site behind a proxy that reaches it through a second proxy,
+/// listening with `listener` and reached as `upstream_proxy` says. The second
+/// proxy intercepts too, with the same CA as the first.
+fn set_up(listener: Listener, upstream_proxy: fn(&str) -> UpstreamProxy) -> Harness {
+    let site = TestSite::start();
+    let next_hop_address = spawn_proxy(site.proxy(mitm_layer(forward)), listener);
+    let proxy = site
+        .proxy(mitm_layer(forward))
+        // A chained proxy can only find the server by asking the next hop
+        .resolver(SystemResolver)
+        .additional_root_certificates(vec![
+            site.ca.root_certificate(),
+            site.proxy_ca.root_certificate(),
+        ])
+        .upstream_proxy(upstream_proxy(&next_hop_address.to_string()));
+    Harness::serve(site, proxy)
+}
+
+/// The path the test server saw
+async fn get_through_chain(test_harness: &Harness) -> String {
+    let response_body = test_harness
+        .client
+        .get(format!(
+            "https://{}/chained",
+            test_harness.test_site_and_port
+        ))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    let deserialized: MyRequest = serde_json::from_str(&response_body).unwrap();
+    deserialized.path.to_string()
+}
+
+#[tokio::test]
+async fn requests_reach_server_through_http_proxy() {
+    let test_harness = set_up(Listener::Http, UpstreamProxy::http);
+    assert_eq!(get_through_chain(&test_harness).await, "/chained");
+}
+
+#[tokio::test]
+async fn requests_reach_server_through_socks5_proxy() {
+    let test_harness = set_up(Listener::Socks5, UpstreamProxy::socks5);
+    assert_eq!(get_through_chain(&test_harness).await, "/chained");
+}
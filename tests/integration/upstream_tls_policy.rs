@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+
+use hyper::{Body, Request, StatusCode};
+use third_wheel::*;
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::harness::{forward, Harness, MyRequest, TestSite};
+
+/// The test site behind a proxy that doesn't trust the site's CA, leaving its
+/// certificate to the policy `make_policy` gives for the site
+fn set_up(make_policy: impl FnOnce(&TestSite) -> UpstreamTlsPolicy) -> Harness {
+    set_up_with(make_policy, InvalidUpstreamCertificate::default())
+}
+
+/// As `set_up`, with the proxy doing as `invalid_upstream_certificate` says
+/// when the policy rejects the certificate
+fn set_up_with(
+    make_policy: impl FnOnce(&TestSite) -> UpstreamTlsPolicy,
+    invalid_upstream_certificate: InvalidUpstreamCertificate,
+) -> Harness {
+    let site = TestSite::start();
+    let proxy = site
+        .proxy(mitm_layer(forward))
+        .additional_root_certificates(Vec::new())
+        .upstream_tls_policy(make_policy(&site))
+        .invalid_upstream_certificate(invalid_upstream_certificate);
+    Harness::serve(site, proxy)
+}
+
+async fn get_through_proxy(test_harness: &Harness) -> Result<String, reqwest::Error> {
+    get_with_client(test_harness, &test_harness.client).await
+}
+
+async fn get_with_client(
+    test_harness: &Harness,
+    client: &reqwest::Client,
+) -> Result<String, reqwest::Error> {
+    client
+        .get(format!("https://{}/", test_harness.test_site_and_port))
+        .send()
+        .await?
+        .text()
+        .await
+}
+
+#[tokio::test]
+async fn untrusted_server_is_refused_by_default() {
+    let test_harness = set_up(|_| UpstreamTlsPolicy::Verify);
+    assert!(get_through_proxy(&test_harness).await.is_err());
+}
+
+/// The status and body of the proxy's answer to a CONNECT to `authority`
+async fn connect_response(test_harness: &Harness, authority: &str) -> (StatusCode, String) {
+    let stream = TcpStream::connect(test_harness.third_wheel_address)
+        .await
+        .unwrap();
+    let (mut sender, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+    tokio::spawn(connection);
+    let response = sender
+        .send_request(Request::connect(authority).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn refusal_says_why_the_policy_rejected_the_certificate() {
+    let test_harness = set_up(|_| UpstreamTlsPolicy::custom(|_, _| false));
+    let (status, body) = connect_response(&test_harness, &test_harness.test_site_and_port).await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    let domain = test_harness.test_site_and_port.split(':').next().unwrap();
+    assert_eq!(
+        body,
+        format!(
+            "upstream certificate rejected: certificate of {} refused by policy",
+            domain
+        )
+    );
+}
+
+#[tokio::test]
+async fn refusal_says_why_verification_failed() {
+    let test_harness = set_up(|_| UpstreamTlsPolicy::Verify);
+    let (status, body) = connect_response(&test_harness, &test_harness.test_site_and_port).await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    // OpenSSL and rustls word it differently, but both name the problem
+    assert!(
+        body.contains("unable to get local issuer certificate") || body.contains("UnknownIssuer"),
+        "{}",
+        body
+    );
+}
+
+#[tokio::test]
+async fn untrusted_server_is_accepted_when_invalid_certificates_are() {
+    let test_harness = set_up(|_| UpstreamTlsPolicy::AcceptInvalid);
+    let response_body = get_through_proxy(&test_harness).await.unwrap();
+    let deserialized: MyRequest = serde_json::from_str(&response_body).unwrap();
+    assert_eq!(deserialized.method, "GET");
+}
+
+#[tokio::test]
+async fn untrusted_server_is_accepted_only_with_matching_pin() {
+    let test_harness = set_up(|site| {
+        let mut pins = HashMap::new();
+        pins.insert(
+            site.domain.clone(),
+            vec![spki_sha256(&site.certificate()).unwrap()],
+        );
+        UpstreamTlsPolicy::Pinned(pins)
+    });
+    assert!(get_through_proxy(&test_harness).await.is_ok());
+
+    let test_harness = set_up(|site| {
+        let mut pins = HashMap::new();
+        pins.insert(site.domain.clone(), vec![[0; 32]]);
+        UpstreamTlsPolicy::Pinned(pins)
+    });
+    assert!(get_through_proxy(&test_harness).await.is_err());
+}
+
+#[tokio::test]
+async fn custom_policy_decides_on_untrusted_server() {
+    let test_harness = set_up(|site| {
+        let domain = site.domain.clone();
+        UpstreamTlsPolicy::custom(move |host, _| host == domain)
+    });
+    assert!(get_through_proxy(&test_harness).await.is_ok());
+
+    let test_harness = set_up(|_| UpstreamTlsPolicy::custom(|_, _| false));
+    assert!(get_through_proxy(&test_harness).await.is_err());
+}
+
+#[tokio::test]
+async fn untrusted_server_is_mirrored_with_self_signed_certificate() {
+    let test_harness = set_up_with(
+        |_| UpstreamTlsPolicy::Verify,
+        InvalidUpstreamCertificate::MirrorSelfSigned,
+    );
+    assert!(get_through_proxy(&test_harness).await.is_err());
+    let insecure_client = test_harness.insecure_proxied_client();
+    assert!(get_with_client(&test_harness, &insecure_client)
+        .await
+        .is_ok());
+}
+
+#[tokio::test]
+async fn untrusted_server_is_mirrored_with_expired_certificate() {
+    let test_harness = set_up_with(
+        |_| UpstreamTlsPolicy::Verify,
+        InvalidUpstreamCertificate::MirrorExpired,
+    );
+    assert!(get_through_proxy(&test_harness).await.is_err());
+    let insecure_client = test_harness.insecure_proxied_client();
+    assert!(get_with_client(&test_harness, &insecure_client)
+        .await
+        .is_ok());
+}
+
+/// Listens in front of the test site's TLS server, closing the first
+/// connection before its handshake and relaying the rest to the site.
+/// Returns the port it listens on.
+fn spawn_flaky_relay(site: &TestSite) -> u16 {
+    let site_port = site.port;
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let listener = TcpListener::from_std(listener).unwrap();
+        drop(listener.accept().await.unwrap());
+        loop {
+            let (mut client, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut server = TcpStream::connect(("127.0.0.1", site_port)).await.unwrap();
+                tokio::io::copy_bidirectional(&mut client, &mut server)
+                    .await
+                    .ok();
+            });
+        }
+    });
+    port
+}
+
+#[tokio::test]
+async fn handshake_failing_for_other_reasons_is_not_mirrored() {
+    let site = TestSite::start();
+    let relay_port = spawn_flaky_relay(&site);
+    let proxy = site
+        .proxy(mitm_layer(forward))
+        .invalid_upstream_certificate(InvalidUpstreamCertificate::MirrorSelfSigned);
+    let test_harness = Harness::serve(site, proxy);
+
+    // A retry without verification would get through the relay, but the
+    // certificate was never the problem
+    let authority = format!("{}:{}", test_harness.site.domain, relay_port);
+    let (status, _) = connect_response(&test_harness, &authority).await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+}
@@ -0,0 +1,100 @@
+use futures::{SinkExt, StreamExt};
+use third_wheel::*;
+use tokio::net::TcpStream;
+use tokio_native_tls::TlsStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+use crate::harness::{forward, set_up_for_trivial_mitm_test, Harness, TestSite};
+
+/// A WebSocket connection through the proxy to the test server's echo
+/// endpoint
+async fn connect(test_harness: &Harness) -> WebSocketStream<TlsStream<TcpStream>> {
+    let (domain, _) = test_harness.test_site_and_port.split_once(':').unwrap();
+    let stream = test_harness.tunnel(&test_harness.test_site_and_port).await;
+    let connector = native_tls::TlsConnector::builder()
+        .add_root_certificate(test_harness.third_wheel_root_certificate())
+        .request_alpns(&["http/1.1"])
+        .build()
+        .unwrap();
+    let stream = tokio_native_tls::TlsConnector::from(connector)
+        .connect(domain, stream)
+        .await
+        .unwrap();
+    let (websocket, _) = tokio_tungstenite::client_async(
+        format!("wss://{}/ws", test_harness.test_site_and_port),
+        stream,
+    )
+    .await
+    .unwrap();
+    websocket
+}
+
+async fn next_message(websocket: &mut WebSocketStream<TlsStream<TcpStream>>) -> Message {
+    websocket.next().await.unwrap().unwrap()
+}
+
+#[tokio::test]
+async fn websocket_messages_are_relayed() {
+    let test_harness = set_up_for_trivial_mitm_test().await;
+    let mut websocket = connect(&test_harness).await;
+
+    websocket.send(Message::text("hello")).await.unwrap();
+    assert_eq!(next_message(&mut websocket).await, Message::text("hello"));
+    websocket
+        .send(Message::binary(vec![1, 2, 3]))
+        .await
+        .unwrap();
+    assert_eq!(
+        next_message(&mut websocket).await,
+        Message::binary(vec![1, 2, 3])
+    );
+}
+
+/// Shouts what the client says, drops what it says in a whisper and follows
+/// every reply with a message of its own
+fn interceptor(direction: WebSocketDirection, message: WebSocketMessage) -> Vec<WebSocketMessage> {
+    match (direction, message) {
+        (WebSocketDirection::ToServer, WebSocketMessage::Text(text)) if text == "psst" => vec![],
+        (WebSocketDirection::ToServer, WebSocketMessage::Text(text)) => {
+            vec![WebSocketMessage::Text(text.to_uppercase())]
+        }
+        (WebSocketDirection::ToClient, message) => {
+            vec![message, WebSocketMessage::Binary(b"injected".to_vec())]
+        }
+        (_, message) => vec![message],
+    }
+}
+
+#[tokio::test]
+async fn websocket_messages_are_intercepted() {
+    let site = TestSite::start();
+    let proxy = site
+        .proxy(mitm_layer(forward))
+        .websocket_interceptor(interceptor);
+    let test_harness = Harness::serve(site, proxy);
+    let mut websocket = connect(&test_harness).await;
+
+    websocket.send(Message::text("hello")).await.unwrap();
+    assert_eq!(next_message(&mut websocket).await, Message::text("HELLO"));
+    assert_eq!(
+        next_message(&mut websocket).await,
+        Message::binary(b"injected".to_vec())
+    );
+
+    websocket.send(Message::text("psst")).await.unwrap();
+    websocket
+        .send(Message::binary(vec![1, 2, 3]))
+        .await
+        .unwrap();
+    assert_eq!(
+        next_message(&mut websocket).await,
+        Message::binary(vec![1, 2, 3])
+    );
+    assert_eq!(
+        next_message(&mut websocket).await,
+        Message::binary(b"injected".to_vec())
+    );
+
+    websocket.close(None).await.unwrap();
+}